@@ -1,5 +1,5 @@
 use bevy::prelude::Resource;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 #[derive(Parser, Debug, Resource)]
@@ -38,6 +38,28 @@ pub struct ProgramArgs {
   #[arg(short = 't', long, default_value_t = 3)]
   pub num_types: usize,
 
+  /// Strategy used to generate the force coefficients.
+  ///
+  /// Ignored if <interaction-spec> is given.
+  #[arg(long, value_enum, default_value_t = Generator::Uniform)]
+  pub generator: Generator,
+
+  /// Lower bound of the generated force coefficients.
+  #[arg(long, default_value_t = -500.0, allow_negative_numbers = true)]
+  pub coeff_min: f32,
+
+  /// Upper bound of the generated force coefficients.
+  #[arg(long, default_value_t = 500.0, allow_negative_numbers = true)]
+  pub coeff_max: f32,
+
+  /// Fraction of coefficients zeroed out by the sparse generator.
+  #[arg(long, default_value_t = 0.5)]
+  pub sparsity: f64,
+
+  /// Number of type clusters used by the blocks generator.
+  #[arg(long, default_value_t = 2)]
+  pub blocks: usize,
+
   /// Number of particles to be generated initially.
   #[arg(short = 'n', long, default_value_t = 1000)]
   pub num_particles: usize,
@@ -48,4 +70,402 @@ pub struct ProgramArgs {
 
   #[arg(short = 's', default_value_t = 2.0)]
   pub particle_size: f32,
+
+  /// Width of the window in logical pixels. The dish starts out the size of the
+  /// window, and keeps that size when the window is resized.
+  #[arg(long, default_value_t = 2560.0)]
+  pub window_width: f32,
+
+  /// Height of the window in logical pixels.
+  #[arg(long, default_value_t = 1440.0)]
+  pub window_height: f32,
+
+  /// Whether the viewer takes up a whole monitor.
+  #[arg(long, value_enum, default_value_t = DisplayMode::Borderless)]
+  pub display_mode: DisplayMode,
+
+  /// Index of the monitor to open the window on, instead of the primary one.
+  #[arg(long)]
+  pub monitor: Option<usize>,
+
+  /// Draw frames as fast as possible instead of syncing with the display.
+  #[arg(long)]
+  pub no_vsync: bool,
+
+  /// Let the window be resized. The dish stays the same size, and the view
+  /// zooms to keep it in sight.
+  #[arg(long)]
+  pub resizable: bool,
+
+  /// How particles are drawn in the viewer.
+  #[arg(long, value_enum, default_value_t = Renderer::Mesh)]
+  pub renderer: Renderer,
+
+  /// Color palette for the particle types.
+  ///
+  /// Without this, colors stored in the spec are used if it has any, and the
+  /// random palette otherwise. The chosen colors are stored in dumped specs.
+  /// In the viewer, L switches to the next palette and Shift+L picks a new
+  /// palette seed.
+  #[arg(long, value_enum)]
+  pub palette: Option<Palette>,
+
+  /// Seed for the palette. Defaults to the spec's seed, so a spec looks the
+  /// same every time it's opened.
+  #[arg(long)]
+  pub palette_seed: Option<u64>,
+
+  /// What particle colors show at startup. C cycles through the modes in the
+  /// viewer.
+  #[arg(long, value_enum, default_value_t = ColorMode::Type)]
+  pub color_mode: ColorMode,
+
+  /// Draw fading trails behind particles. T toggles them in the viewer.
+  #[arg(long)]
+  pub trails: bool,
+
+  /// Number of past positions kept for each trail.
+  #[arg(long, default_value_t = 20)]
+  pub trail_length: usize,
+
+  /// Number of ticks between trail positions.
+  #[arg(long, default_value_t = 3)]
+  pub trail_interval: u64,
+
+  /// Show a heatmap of particle density behind the particles, blending the
+  /// colors of the types present in each cell. H toggles it in the viewer.
+  ///
+  /// Large-scale structure stays visible when zoomed out far enough for
+  /// single particles to vanish.
+  #[arg(long)]
+  pub density: bool,
+
+  /// Side length of the density grid cells.
+  #[arg(long, default_value_t = 16.0)]
+  pub density_cell_size: f32,
+
+  /// Show the spatial index used for the neighbour search: bucket grid lines
+  /// and occupancy counts, plus the buckets searched around the selected
+  /// particle and its kernel radii. B toggles it in the viewer.
+  #[arg(long)]
+  pub index_overlay: bool,
+
+  /// Draw arrows for the net acceleration of particles, and for the pairwise
+  /// contributions of the selected particle's neighbours, green where they
+  /// attract and red where they repel. The pairwise arrows are those of the
+  /// last force computation, and add up to the net one less friction. V
+  /// cycles through the options in the viewer.
+  #[arg(long, value_enum)]
+  pub force_vectors: Option<ForceVectors>,
+
+  /// Draw the dish tiled 3x3 with an outline around the dish itself, so
+  /// structures crossing an edge show up whole. G toggles it in the viewer,
+  /// and X shifts all particles to bring the point under the cursor to the
+  /// middle of the dish. Tiling needs --renderer instanced.
+  #[arg(long)]
+  pub tiled: bool,
+
+  /// Another interaction spec to run next to the main one, in a dish and
+  /// viewport of its own with the same number of particles. Repeat to compare
+  /// several.
+  ///
+  /// Statistics, overlays and selection only cover the main dish.
+  #[arg(long)]
+  pub compare: Vec<PathBuf>,
+
+  /// Seed for where particles start out in the viewer. Dishes added with
+  /// --compare get seeds derived from it, so a comparison can be rerun
+  /// exactly. Random if not given; the seed used is logged.
+  #[arg(long)]
+  pub layout_seed: Option<u64>,
+
+  /// Pan and zoom all dishes together, instead of each viewport on its own.
+  #[arg(long)]
+  pub sync_cameras: bool,
+
+  /// Jump straight to each zoom level instead of easing into it.
+  #[arg(long)]
+  pub instant_zoom: bool,
+
+  /// RON file binding actions to keys and mouse buttons, e.g.
+  /// `(bindings: { Pause: [(input: Key(KeyP))], Pan: [(input: Mouse(Right), modifiers: [Ctrl])] })`.
+  /// Actions it doesn't list keep their default bindings, shown with F1. A
+  /// key or button can only be bound to one action, apart from the replay
+  /// controls.
+  #[arg(long)]
+  pub keymap: Option<PathBuf>,
+
+  /// Record observables to this file while the simulation runs.
+  #[arg(long)]
+  pub record: Option<PathBuf>,
+
+  /// Number of ticks between recorded samples.
+  #[arg(long, default_value_t = 10)]
+  pub record_interval: u64,
+
+  #[arg(long, value_enum, default_value_t = RecordFormat::Csv)]
+  pub record_format: RecordFormat,
+
+  /// Also record the position and type of every particle.
+  ///
+  /// With the CSV format these go to a separate file, named after the --record
+  /// path with a "-particles" suffix.
+  #[arg(long)]
+  pub record_particles: bool,
+
+  /// Ticks between cluster labelling passes.
+  #[arg(long, default_value_t = 10)]
+  pub cluster_interval: u64,
+
+  /// Directory that screenshots (taken with F12 or --screenshot-at) are saved
+  /// to.
+  #[arg(long, default_value = "screenshots")]
+  pub screenshot_dir: PathBuf,
+
+  /// Take a screenshot once the simulation reaches this tick.
+  #[arg(long)]
+  pub screenshot_at: Option<u64>,
+
+  /// Save every rendered frame to this directory as a numbered PNG sequence.
+  ///
+  /// While capturing, each frame advances the simulation by exactly
+  /// --capture-interval ticks, however long it takes to render, so the
+  /// sequence plays back at a steady speed regardless of the frame rate.
+  #[arg(long)]
+  pub capture_frames: Option<PathBuf>,
+
+  /// Number of ticks between captured frames.
+  #[arg(long, default_value_t = 2)]
+  pub capture_interval: u64,
+
+  /// Play back a trajectory instead of running the simulation.
+  ///
+  /// The file must have been recorded with --record-format binary and
+  /// --record-particles. Particle colors come from <interaction-spec> if
+  /// given. Space pauses, the arrow keys step through samples (skipping a
+  /// tenth of the recording with Shift), Home and End jump to either end, and
+  /// [ and ] halve or double the playback speed.
+  #[arg(long)]
+  pub replay: Option<PathBuf>,
+
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+  /// Search for interesting specs without opening a window.
+  ///
+  /// Starting from <interaction-spec> (or a generated spec), each generation
+  /// runs mutated copies of the best spec found so far and keeps the one with
+  /// the highest fitness. Every candidate is written to the output directory.
+  Evolve(EvolveArgs),
+  /// Run every combination of parameters from a sweep file without opening a
+  /// window, writing a table of summary statistics and each run's spec.
+  Sweep(SweepArgs),
+  /// Run a spec without opening a window, writing a numbered PNG sequence
+  /// drawn by the software renderer.
+  Export(ExportArgs),
+}
+
+/// Options shared by all modes running the simulation without a window.
+#[derive(Args, Debug, Clone)]
+pub struct HeadlessArgs {
+  /// Number of simulation ticks to run before measuring.
+  #[arg(long, default_value_t = 2000)]
+  pub ticks: usize,
+
+  #[arg(long, default_value_t = 2560.0)]
+  pub dish_width: f32,
+
+  #[arg(long, default_value_t = 1440.0)]
+  pub dish_height: f32,
+
+  /// Size of rendered images relative to the dish.
+  #[arg(long, default_value_t = 0.5)]
+  pub image_scale: f32,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct EvolveArgs {
+  #[command(flatten)]
+  pub headless: HeadlessArgs,
+
+  #[arg(long, default_value_t = 10)]
+  pub generations: usize,
+
+  /// Number of mutated candidates evaluated per generation.
+  #[arg(long, default_value_t = 8)]
+  pub population: usize,
+
+  #[arg(long, value_enum, default_value_t = Fitness::Clustering)]
+  pub fitness: Fitness,
+
+  /// Size of coefficient perturbations, as a fraction of the coefficient
+  /// range given by --coeff-min and --coeff-max.
+  #[arg(long, default_value_t = 0.1)]
+  pub mutation_strength: f32,
+
+  /// Directory receiving candidate specs. Defaults to a timestamped directory.
+  #[arg(long)]
+  pub output_dir: Option<PathBuf>,
+
+  /// Seed for mutations and initial particle placement.
+  #[arg(long)]
+  pub seed: Option<u64>,
+
+  /// Write a PNG of each candidate's final state next to its spec.
+  #[arg(long)]
+  pub thumbnails: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SweepArgs {
+  /// Path to the sweep definition file.
+  ///
+  /// This is a RON struct whose fields each hold `Fixed(value)`,
+  /// `List([values...])` or `Range(start: a, end: b, steps: n)`. `seeds` is
+  /// required; `num_types`, `num_particles` and any kernel parameter (e.g.
+  /// `friction`, `peak_distance`) are optional and fall back to the command
+  /// line or built-in defaults.
+  #[arg()]
+  pub definition: PathBuf,
+
+  #[command(flatten)]
+  pub headless: HeadlessArgs,
+
+  /// Directory receiving the results table and spec files. Defaults to a
+  /// timestamped directory.
+  #[arg(long)]
+  pub output_dir: Option<PathBuf>,
+
+  #[arg(long, value_enum, default_value_t = TableFormat::Csv)]
+  pub format: TableFormat,
+
+  /// Write a PNG of each run's final state next to its spec.
+  #[arg(long)]
+  pub thumbnails: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ExportArgs {
+  #[command(flatten)]
+  pub headless: HeadlessArgs,
+
+  /// Number of ticks between frames.
+  #[arg(long, default_value_t = 10)]
+  pub frame_interval: usize,
+
+  /// Directory receiving the frames. Defaults to a timestamped directory.
+  #[arg(long)]
+  pub output_dir: Option<PathBuf>,
+
+  /// Seed for initial particle placement.
+  #[arg(long)]
+  pub seed: Option<u64>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+  Csv,
+  /// One JSON object per sample, holding the scalar observables.
+  JsonLines,
+  /// Compact little-endian binary, see the `record` module for the layout.
+  Binary,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+  Csv,
+  Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fitness {
+  /// Favours particles gathering in dense groups with empty space between.
+  Clustering,
+  /// Favours fast-moving particles.
+  Motion,
+  /// Favours a wide variety of local densities.
+  Diversity,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayMode {
+  /// A decorated window of the given size.
+  Windowed,
+  /// A borderless window covering the monitor.
+  Borderless,
+  /// Exclusive fullscreen, at the video mode closest to the window size.
+  Fullscreen,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renderer {
+  /// All particles drawn as billboards from one instance buffer, in a single
+  /// draw call. Handles very large particle counts.
+  Instanced,
+  /// One sphere mesh entity per particle, with child entities for the
+  /// selection and highlight markers.
+  Mesh,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForceVectors {
+  /// Only the selected particle and the particles around it.
+  Selected,
+  /// Every particle.
+  All,
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+  /// The color of the particle's type.
+  #[default]
+  Type,
+  /// A heatmap of speed.
+  Speed,
+  /// A heatmap of the magnitude of acceleration.
+  Acceleration,
+  /// A heatmap of the number of particles in nearby spatial index buckets.
+  Density,
+  /// A color per cluster, dim grey outside clusters.
+  Cluster,
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Palette {
+  /// Hues spread over a random arc, with random lightness and chroma.
+  #[default]
+  Random,
+  /// Evenly spaced, saturated hues.
+  Rainbow,
+  /// Evenly spaced, light and muted hues.
+  Pastel,
+  /// Reds, oranges and yellows.
+  Warm,
+  /// Greens, blues and purples.
+  Cool,
+  /// Samples of the viridis color map.
+  Viridis,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Generator {
+  /// Every coefficient drawn uniformly from the coefficient range.
+  Uniform,
+  /// Uniform, but type A affects B exactly as B affects A.
+  Symmetric,
+  /// Uniform, but type A affects B with the opposite sign to how B affects A,
+  /// which tends to produce chasing behaviour.
+  Antisymmetric,
+  /// Uniform, with a --sparsity fraction of coefficients set to zero.
+  Sparse,
+  /// Normally distributed around the middle of the coefficient range, with the
+  /// range spanning six standard deviations.
+  Gaussian,
+  /// Each type chases the next one, which flees from it, closing a cycle.
+  PredatorPrey,
+  /// Types split into --blocks clusters that attract within and repel between.
+  Blocks,
 }
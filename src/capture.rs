@@ -0,0 +1,97 @@
+use bevy::prelude::*;
+use bevy::render::view::screenshot::{save_to_disk, Screenshot};
+
+use chrono::Utc;
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::core::*;
+use crate::keymap::{Action, Actions};
+use crate::observables::Observables;
+
+/// Numbered PNG sequence being written while the simulation runs.
+#[derive(Resource)]
+pub struct FrameCapture {
+  dir: PathBuf,
+  interval: u64,
+  next_frame: u32,
+  last_tick: u64,
+}
+
+impl FrameCapture {
+  /// Creates the output directory requested on the command line, if any.
+  pub fn start(args: &ProgramArgs) -> io::Result<Option<FrameCapture>> {
+    let dir = match &args.capture_frames {
+      Some(dir) => dir,
+      None => return Ok(None),
+    };
+    fs::create_dir_all(dir)?;
+    Ok(Some(FrameCapture {
+      dir: dir.clone(),
+      interval: args.capture_interval.max(1),
+      next_frame: 0,
+      last_tick: 0,
+    }))
+  }
+
+  /// Simulated time covered by each captured frame.
+  pub fn frame_duration(&self) -> Duration {
+    Duration::from_secs_f64(DELTA_TIME) * self.interval as u32
+  }
+}
+
+pub fn capture_frame(
+  mut commands: Commands,
+  capture: Option<ResMut<FrameCapture>>,
+  observables: Res<Observables>,
+) {
+  let mut capture = match capture {
+    Some(capture) => capture,
+    None => return,
+  };
+  // Nothing has moved while paused, so there's no new frame worth keeping.
+  if observables.tick == capture.last_tick {
+    return;
+  }
+  let path = capture
+    .dir
+    .join(format!("frame-{:06}.png", capture.next_frame));
+  commands
+    .spawn(Screenshot::primary_window())
+    .observe(save_to_disk(path));
+  capture.next_frame += 1;
+  capture.last_tick = observables.tick;
+}
+
+pub fn take_screenshot(
+  mut commands: Commands,
+  args: Res<ProgramArgs>,
+  actions: Actions,
+  observables: Res<Observables>,
+  mut scheduled_taken: Local<bool>,
+) {
+  let scheduled = !*scheduled_taken
+    && args
+      .screenshot_at
+      .is_some_and(|tick| observables.tick >= tick);
+  if !scheduled && !actions.just_pressed(Action::Screenshot) {
+    return;
+  }
+  *scheduled_taken |= scheduled;
+  if let Err(err) = fs::create_dir_all(&args.screenshot_dir) {
+    error!("cannot create {}: {}", args.screenshot_dir.display(), err);
+    return;
+  }
+  let path = args.screenshot_dir.join(format!(
+    "screenshot-{}-tick-{}.png",
+    Utc::now().format("%F-%H-%M-%S"),
+    observables.tick
+  ));
+  info!("saving screenshot to {}", path.display());
+  commands
+    .spawn(Screenshot::primary_window())
+    .observe(save_to_disk(path));
+}
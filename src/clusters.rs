@@ -0,0 +1,185 @@
+use bevy::prelude::*;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::args::ColorMode;
+use crate::coloring::Coloring;
+use crate::core::*;
+use crate::observables::{Contacts, Observables, MIN_CLUSTER_SIZE};
+
+/// Number of distinct colors cycled through when coloring by cluster.
+const CLUSTER_COLOR_COUNT: usize = 16;
+
+/// Clusters found by the most recent labelling pass.
+///
+/// IDs persist across passes: a cluster keeps the ID shared by most of its
+/// members last time, so a drifting or growing cluster stays recognisable.
+#[derive(Default, Resource)]
+pub struct Clusters {
+  pub labels: HashMap<Entity, u32>,
+  /// Number of members of each cluster, by ID.
+  pub sizes: HashMap<u32, usize>,
+  next_id: u32,
+}
+
+#[derive(Resource)]
+pub struct ClusterMaterials {
+  pub clusters: Vec<Handle<StandardMaterial>>,
+  pub unclustered: Handle<StandardMaterial>,
+}
+
+impl ClusterMaterials {
+  pub fn get(&self, label: Option<u32>) -> Handle<StandardMaterial> {
+    match label {
+      Some(id) => self.clusters[id as usize % self.clusters.len()].clone(),
+      None => self.unclustered.clone(),
+    }
+  }
+}
+
+pub fn init_cluster_materials(
+  mut commands: Commands,
+  mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+  let mut material = |color: Color| {
+    materials.add(StandardMaterial {
+      base_color: color,
+      double_sided: true,
+      unlit: true,
+      ..Default::default()
+    })
+  };
+  // Stepping hues by the golden angle keeps consecutive IDs far apart.
+  let clusters = (0..CLUSTER_COLOR_COUNT)
+    .map(|i| material(Color::hsl((i as f32 * 137.5) % 360.0, 0.8, 0.6)))
+    .collect();
+  let unclustered = material(Color::srgb(0.25, 0.25, 0.25));
+  commands.insert_resource(ClusterMaterials {
+    clusters,
+    unclustered,
+  });
+}
+
+/// Labels the groups of touching particles found by `update_observables`
+/// this tick.
+pub fn label_clusters(
+  args: Res<ProgramArgs>,
+  state: Res<State<SimState>>,
+  coloring: Res<Coloring>,
+  observables: Res<Observables>,
+  mut contacts: ResMut<Contacts>,
+  mut clusters: ResMut<Clusters>,
+) {
+  if state.get() == &SimState::Paused || !labelling_due(&args, &coloring, observables.tick) {
+    return;
+  }
+  let components = contacts.groups.components(MIN_CLUSTER_SIZE);
+  clusters.relabel(&contacts.entities, components);
+}
+
+/// Whether clusters are labelled on `tick`. The labels are only shown when
+/// coloring by cluster, so they aren't kept up to date otherwise.
+pub fn labelling_due(args: &ProgramArgs, coloring: &Coloring, tick: u64) -> bool {
+  coloring.mode == ColorMode::Cluster && tick.is_multiple_of(args.cluster_interval.max(1))
+}
+
+impl Clusters {
+  /// Replaces the current labels, matching each new cluster to the old ID
+  /// most of its members carried. Larger clusters get first pick, and IDs
+  /// with no match are never reused.
+  pub fn relabel(&mut self, entities: &[Entity], mut components: Vec<Vec<usize>>) {
+    components.sort_by_key(|members| std::cmp::Reverse(members.len()));
+    let mut labels = HashMap::new();
+    let mut sizes = HashMap::new();
+    let mut claimed = HashSet::new();
+    for members in components {
+      let mut overlaps: HashMap<u32, usize> = HashMap::new();
+      for &i in members.iter() {
+        if let Some(&id) = self.labels.get(&entities[i]) {
+          *overlaps.entry(id).or_default() += 1;
+        }
+      }
+      let matched = overlaps
+        .into_iter()
+        .filter(|(id, _)| !claimed.contains(id))
+        .max_by_key(|&(id, overlap)| (overlap, std::cmp::Reverse(id)))
+        .map(|(id, _)| id);
+      let id = matched.unwrap_or_else(|| {
+        self.next_id += 1;
+        self.next_id - 1
+      });
+      claimed.insert(id);
+      sizes.insert(id, members.len());
+      for &i in members.iter() {
+        labels.insert(entities[i], id);
+      }
+    }
+    self.labels = labels;
+    self.sizes = sizes;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entities(count: u32) -> Vec<Entity> {
+    (0..count).map(Entity::from_raw).collect()
+  }
+
+  fn ids(
+    clusters: &Clusters,
+    entities: &[Entity],
+    members: impl Iterator<Item = usize>,
+  ) -> HashSet<u32> {
+    members.map(|i| clusters.labels[&entities[i]]).collect()
+  }
+
+  #[test]
+  fn drifting_cluster_keeps_its_id() {
+    let entities = entities(20);
+    let mut clusters = Clusters::default();
+    clusters.relabel(&entities, vec![(0..6).collect(), (10..15).collect()]);
+    let first = clusters.labels[&entities[0]];
+    let second = clusters.labels[&entities[10]];
+    assert_ne!(first, second);
+
+    // Each loses a member at one end and gains one at the other.
+    clusters.relabel(&entities, vec![(1..7).collect(), (11..16).collect()]);
+    assert_eq!(ids(&clusters, &entities, 1..7), HashSet::from([first]));
+    assert_eq!(ids(&clusters, &entities, 11..16), HashSet::from([second]));
+    assert!(!clusters.labels.contains_key(&entities[0]));
+    assert_eq!(clusters.sizes, HashMap::from([(first, 6), (second, 5)]));
+  }
+
+  #[test]
+  fn split_keeps_the_id_on_the_larger_part() {
+    let entities = entities(10);
+    let mut clusters = Clusters::default();
+    clusters.relabel(&entities, vec![(0..10).collect()]);
+    let id = clusters.labels[&entities[0]];
+
+    clusters.relabel(&entities, vec![(7..10).collect(), (0..7).collect()]);
+    assert_eq!(ids(&clusters, &entities, 0..7), HashSet::from([id]));
+    let smaller = ids(&clusters, &entities, 7..10);
+    assert_eq!(smaller.len(), 1);
+    assert!(!smaller.contains(&id));
+  }
+
+  #[test]
+  fn merge_keeps_one_id() {
+    let entities = entities(10);
+    let mut clusters = Clusters::default();
+    clusters.relabel(&entities, vec![(0..6).collect(), (6..10).collect()]);
+    let larger = clusters.labels[&entities[0]];
+    let smaller = clusters.labels[&entities[6]];
+
+    clusters.relabel(&entities, vec![(0..10).collect()]);
+    assert_eq!(ids(&clusters, &entities, 0..10), HashSet::from([larger]));
+    assert_eq!(clusters.sizes, HashMap::from([(larger, 10)]));
+
+    // The absorbed cluster's ID isn't handed out again.
+    clusters.relabel(&entities, vec![(0..5).collect(), (5..10).collect()]);
+    assert!(!ids(&clusters, &entities, 0..10).contains(&smaller));
+  }
+}
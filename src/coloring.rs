@@ -0,0 +1,147 @@
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use clap::ValueEnum;
+
+use crate::args::ColorMode;
+use crate::clusters::{ClusterMaterials, Clusters};
+use crate::core::*;
+use crate::palette;
+
+/// Number of steps in the heatmap gradient.
+const HEATMAP_STEPS: usize = 32;
+/// Values at or above this percentile get the hottest color, so a few fast
+/// outliers don't wash out everything else.
+const HEATMAP_PERCENTILE: f32 = 0.95;
+
+#[derive(Resource, Debug)]
+pub struct Coloring {
+  pub mode: ColorMode,
+}
+
+impl Coloring {
+  pub fn from_args(args: &ProgramArgs) -> Coloring {
+    Coloring {
+      mode: args.color_mode,
+    }
+  }
+
+  pub fn next_mode(&mut self) {
+    let modes = ColorMode::value_variants();
+    let index = modes
+      .iter()
+      .position(|&mode| mode == self.mode)
+      .unwrap_or_default();
+    self.mode = modes[(index + 1) % modes.len()];
+  }
+}
+
+#[derive(Resource)]
+pub struct HeatmapMaterials(Vec<Handle<StandardMaterial>>);
+
+impl HeatmapMaterials {
+  /// Material for a value scaled to [0, 1].
+  fn get(&self, t: f32) -> Handle<StandardMaterial> {
+    let step = (t.clamp(0.0, 1.0) * (self.0.len() - 1) as f32).round() as usize;
+    self.0[step].clone()
+  }
+}
+
+pub fn init_heatmap_materials(
+  mut commands: Commands,
+  mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+  let heatmap = (0..HEATMAP_STEPS)
+    .map(|step| {
+      materials.add(StandardMaterial {
+        base_color: palette::viridis(step as f32 / (HEATMAP_STEPS - 1) as f32),
+        double_sided: true,
+        unlit: true,
+        ..Default::default()
+      })
+    })
+    .collect();
+  commands.insert_resource(HeatmapMaterials(heatmap));
+}
+
+type ColoredParticles<'w, 's> = Query<
+  'w,
+  's,
+  (
+    Entity,
+    &'static Transform,
+    &'static LastPosition,
+    &'static Acceleration,
+    &'static InteractionId,
+    &'static mut MeshMaterial3d<StandardMaterial>,
+  ),
+>;
+
+/// Assigns every particle the material for the current color mode.
+///
+/// Type and cluster colors only change along with the mode or the cluster
+/// labels, while heatmaps follow the particles every frame.
+#[allow(clippy::too_many_arguments)]
+pub fn update_colors(
+  coloring: Res<Coloring>,
+  state: Res<State<SimState>>,
+  particle_spec: Res<ParticleSpec>,
+  sim_region: Res<SimRegion>,
+  clusters: Res<Clusters>,
+  cluster_materials: Res<ClusterMaterials>,
+  heatmap: Res<HeatmapMaterials>,
+  mut particles: ColoredParticles,
+) {
+  match coloring.mode {
+    ColorMode::Type => {
+      if !coloring.is_changed() {
+        return;
+      }
+      for (.., interaction, mut material) in particles.iter_mut() {
+        material.0 = particle_spec.materials[interaction.0].clone();
+      }
+    }
+    ColorMode::Cluster => {
+      if !coloring.is_changed() && !clusters.is_changed() {
+        return;
+      }
+      for (entity, .., mut material) in particles.iter_mut() {
+        material.0 = cluster_materials.get(clusters.labels.get(&entity).copied());
+      }
+    }
+    ColorMode::Speed | ColorMode::Acceleration | ColorMode::Density => {
+      if !coloring.is_changed() && state.get() == &SimState::Paused {
+        return;
+      }
+      let values: Vec<f32> = particles
+        .iter()
+        .map(
+          |(_, transform, last_pos, acceleration, ..)| match coloring.mode {
+            ColorMode::Speed => (transform.translation.xy() - last_pos.0).length(),
+            ColorMode::Acceleration => acceleration.0.length(),
+            _ => sim_region
+              .get_entities_by_position(transform.translation.x, transform.translation.y)
+              .count() as f32,
+          },
+        )
+        .collect();
+      let scale = percentile(values.clone(), HEATMAP_PERCENTILE);
+      for ((.., mut material), value) in particles.iter_mut().zip(values) {
+        let handle = heatmap.get(if scale > 0.0 { value / scale } else { 0.0 });
+        // Only touching changed materials keeps rebatching to a minimum.
+        if material.0 != handle {
+          material.0 = handle;
+        }
+      }
+    }
+  }
+}
+
+pub fn percentile(mut values: Vec<f32>, fraction: f32) -> f32 {
+  if values.is_empty() {
+    return 0.0;
+  }
+  let index = ((values.len() - 1) as f32 * fraction).round() as usize;
+  *values
+    .select_nth_unstable_by(index, |a, b| a.total_cmp(b))
+    .1
+}
@@ -1,5 +1,7 @@
 use bevy::prelude::*;
 
+use chrono::{DateTime, Utc};
+
 use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
@@ -8,28 +10,166 @@ pub use crate::args::*;
 
 pub const DELTA_TIME: f64 = 0.01;
 pub const VELOCITY_THRESHOLD: f32 = 0.0001;
+pub const SPEC_FORMAT_VERSION: u32 = 1;
 
 #[derive(Component, Default, Debug)]
 pub struct Acceleration(pub Vec2);
 #[derive(Component, Default, Debug)]
 pub struct LastPosition(pub Vec2);
+/// Recent positions, oldest first once the ring buffer has filled up.
+#[derive(Component, Default, Debug)]
+pub struct Trail {
+  pub points: Vec<Vec2>,
+  pub next: usize,
+}
+
+impl Trail {
+  pub fn push(&mut self, point: Vec2, capacity: usize) {
+    if self.points.len() < capacity {
+      self.points.push(point);
+    } else if capacity > 0 {
+      self.points[self.next] = point;
+      self.next = (self.next + 1) % capacity;
+    }
+  }
+
+  pub fn clear(&mut self) {
+    self.points.clear();
+    self.next = 0;
+  }
+
+  /// Positions from oldest to newest.
+  pub fn iter(&self) -> impl Iterator<Item = Vec2> + '_ {
+    let (newer, older) = self.points.split_at(self.next);
+    older.iter().chain(newer.iter()).copied()
+  }
+}
 
 #[derive(Bundle, Default)]
 pub struct ParticleBundle {
   pub last_pos: LastPosition,
+  pub trail: Trail,
   pub acceleration: Acceleration,
   pub interaction: InteractionId,
 }
 
-#[derive(Default, Resource)]
+#[derive(Default, Resource, Clone)]
 pub struct ParticleSpec {
   pub interactions: Vec<Interaction>,
+  pub kernel: KernelParams,
+  pub info: SpecInfo,
   pub materials: Vec<Handle<StandardMaterial>>,
 }
-#[derive(Default, Deserialize, Serialize, Debug)]
+
+impl ParticleSpec {
+  pub fn from_file(file: SpecFile) -> ParticleSpec {
+    ParticleSpec {
+      interactions: file.interactions,
+      kernel: file.kernel,
+      info: SpecInfo {
+        name: file.name,
+        description: file.description,
+        seed: file.seed,
+        created: file.created,
+        type_names: file.type_names,
+        colors: file.colors,
+      },
+      ..Default::default()
+    }
+  }
+
+  pub fn to_file(&self) -> SpecFile {
+    let info = self.info.clone();
+    SpecFile {
+      version: SPEC_FORMAT_VERSION,
+      name: info.name,
+      description: info.description,
+      seed: info.seed,
+      created: info.created,
+      kernel: self.kernel,
+      type_names: info.type_names,
+      colors: info.colors,
+      interactions: self.interactions.clone(),
+    }
+  }
+}
+
+/// Descriptive data carried along with a spec. None of it affects the
+/// simulation itself.
+#[derive(Default, Debug, Clone)]
+pub struct SpecInfo {
+  pub name: Option<String>,
+  pub description: Option<String>,
+  pub seed: Option<u64>,
+  pub created: Option<DateTime<Utc>>,
+  pub type_names: Vec<String>,
+  /// sRGB colors, one per type.
+  pub colors: Vec<[f32; 3]>,
+}
+
+/// On-disk layout of an interaction spec.
+///
+/// Files written before versioning was introduced contain only a bare list of
+/// interactions; those are still accepted by the loader.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SpecFile {
+  pub version: u32,
+  #[serde(default)]
+  pub name: Option<String>,
+  #[serde(default)]
+  pub description: Option<String>,
+  #[serde(default)]
+  pub seed: Option<u64>,
+  #[serde(default)]
+  pub created: Option<DateTime<Utc>>,
+  #[serde(default)]
+  pub kernel: KernelParams,
+  #[serde(default)]
+  pub type_names: Vec<String>,
+  #[serde(default)]
+  pub colors: Vec<[f32; 3]>,
+  pub interactions: Vec<Interaction>,
+}
+
+#[derive(Default, Deserialize, Serialize, Debug, Clone)]
 pub struct Interaction {
   pub force_coeffs: Vec<f32>,
 }
+
+/// Shape of the pairwise force as a function of distance.
+///
+/// Below `repulsion_radius` particles repel each other regardless of type,
+/// with a force falling linearly from `repulsion_strength` to zero. Further
+/// out, the type-dependent coefficient is scaled by a triangle centered at
+/// `peak_distance` and `peak_width` wide on each side. Nothing is computed
+/// beyond `interaction_radius`.
+///
+/// `friction` scales the drag on each particle, proportional to its squared
+/// velocity.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct KernelParams {
+  pub repulsion_radius: f32,
+  pub repulsion_strength: f32,
+  pub peak_distance: f32,
+  pub peak_width: f32,
+  pub interaction_radius: f32,
+  pub friction: f32,
+}
+
+impl Default for KernelParams {
+  fn default() -> Self {
+    KernelParams {
+      repulsion_radius: 10.0,
+      repulsion_strength: 1000.0,
+      peak_distance: 30.0,
+      peak_width: 10.0,
+      interaction_radius: 40.0,
+      friction: 0.01,
+    }
+  }
+}
+
 #[derive(Component, Default, Clone, Copy)]
 pub struct InteractionId(pub usize);
 
@@ -51,6 +191,20 @@ impl SimRegion {
     }
   }
 
+  pub fn size(&self) -> Vec2 {
+    2.0 * self.top_right
+  }
+
+  /// Returns an empty region covering the same area, but bucketed with a
+  /// different granularity.
+  pub fn with_granularity(&self, granularity: f32) -> SimRegion {
+    SimRegion {
+      top_right: self.top_right,
+      granularity,
+      ..Default::default()
+    }
+  }
+
   pub fn get_corrected_position_delta(&self, origin: Vec2, target: Vec2) -> Vec2 {
     let delta = target - origin;
     delta + self.get_wrap_around_adjustment(delta)
@@ -71,7 +225,9 @@ impl SimRegion {
     2.0 * adjustment
   }
 
-  fn get_wrapped_buckets(&self, ix: i32, iy: i32) -> Vec<(i32, i32)> {
+  /// The bucket itself, plus its copies across the dish edges when it lies on
+  /// or past the edge.
+  pub fn get_wrapped_buckets(&self, ix: i32, iy: i32) -> Vec<(i32, i32)> {
     let right = (self.top_right.x / self.granularity).round() as i32;
     let top = (self.top_right.y / self.granularity).round() as i32;
     let left = (-self.top_right.x / self.granularity).round() as i32;
@@ -173,7 +329,37 @@ pub struct Selection;
 #[derive(Component, Default, Debug)]
 pub struct Highlight;
 #[derive(Component, Default, Debug)]
-pub struct MainCamera {
-  pub zoom_exponent: i32,
-  pub zoom_base: f32,
+pub struct MainCamera;
+
+/// Zoom of a camera the user pans and zooms.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct CameraZoom {
+  /// The projection scale eases towards `base` to the power of `exponent`.
+  pub exponent: i32,
+  pub base: f32,
+  /// Where the zoom is centred, as an offset in logical pixels from the middle
+  /// of the viewport, y up. The world point there stays put while zooming.
+  pub anchor: Vec2,
+  /// The exponent the view is reset to.
+  pub home_exponent: i32,
+}
+
+impl CameraZoom {
+  pub fn new(base: f32, exponent: i32) -> CameraZoom {
+    CameraZoom {
+      exponent,
+      base,
+      anchor: Vec2::ZERO,
+      home_exponent: exponent,
+    }
+  }
+
+  pub fn scale(&self) -> f32 {
+    self.base.powi(self.exponent)
+  }
 }
+
+/// Tags entities of one of the extra dishes shown next to the main one. The
+/// index starts at 1, and doubles as the render layer the dish is drawn on.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dish(pub usize);
@@ -0,0 +1,195 @@
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+
+use crate::coloring;
+use crate::core::*;
+
+/// The overlay is drawn behind the trails.
+const DENSITY_DEPTH: f32 = -2.0;
+/// Cells at or above this percentile of the occupied cells' densities get full
+/// opacity.
+const DENSITY_PERCENTILE: f32 = 0.95;
+/// Opacity of the densest cells, so particles stay visible on top.
+const MAX_ALPHA: f32 = 0.8;
+
+#[derive(Resource, Debug)]
+pub struct DensitySettings {
+  pub enabled: bool,
+  pub cell_size: f32,
+}
+
+impl DensitySettings {
+  pub fn from_args(args: &ProgramArgs) -> DensitySettings {
+    DensitySettings {
+      enabled: args.density,
+      cell_size: args.density_cell_size.max(1.0),
+    }
+  }
+}
+
+#[derive(Component)]
+pub struct DensityOverlay {
+  image: Handle<Image>,
+}
+
+pub fn init_density_overlay(
+  mut commands: Commands,
+  mut meshes: ResMut<Assets<Mesh>>,
+  mut materials: ResMut<Assets<StandardMaterial>>,
+  mut images: ResMut<Assets<Image>>,
+) {
+  // Sized to the dish on the first update.
+  let image = images.add(Image::new_fill(
+    Extent3d {
+      width: 1,
+      height: 1,
+      depth_or_array_layers: 1,
+    },
+    TextureDimension::D2,
+    &[0, 0, 0, 0],
+    TextureFormat::Rgba8UnormSrgb,
+    RenderAssetUsages::all(),
+  ));
+  commands.spawn((
+    DensityOverlay {
+      image: image.clone(),
+    },
+    Visibility::Hidden,
+    Mesh3d(meshes.add(Rectangle::new(1.0, 1.0))),
+    MeshMaterial3d(materials.add(StandardMaterial {
+      base_color_texture: Some(image),
+      alpha_mode: AlphaMode::Blend,
+      unlit: true,
+      ..Default::default()
+    })),
+    Transform::from_xyz(0.0, 0.0, DENSITY_DEPTH),
+  ));
+}
+
+/// Bins the particles into a grid over the dish and paints each cell with the
+/// count-weighted mix of the type colors in it, more opaque where it's denser.
+#[allow(clippy::too_many_arguments)]
+pub fn update_density_overlay(
+  settings: Res<DensitySettings>,
+  state: Res<State<SimState>>,
+  sim_region: Res<SimRegion>,
+  particle_spec: Res<ParticleSpec>,
+  materials: Res<Assets<StandardMaterial>>,
+  particles: Query<(&Transform, &InteractionId)>,
+  mut overlay: Query<(&DensityOverlay, &mut Transform, &mut Visibility), Without<InteractionId>>,
+  mut images: ResMut<Assets<Image>>,
+) {
+  let (overlay, mut transform, mut visibility) = match overlay.get_single_mut() {
+    Ok(overlay) => overlay,
+    Err(_) => return,
+  };
+  if !settings.enabled {
+    *visibility = Visibility::Hidden;
+    return;
+  }
+  let shown = *visibility != Visibility::Hidden;
+  if shown && !settings.is_changed() && state.get() == &SimState::Paused {
+    return;
+  }
+  *visibility = Visibility::Inherited;
+
+  let dish = sim_region.size();
+  let columns = (dish.x / settings.cell_size).ceil().max(1.0) as usize;
+  let rows = (dish.y / settings.cell_size).ceil().max(1.0) as usize;
+  // The grid may overhang the dish by part of a cell.
+  let grid = Vec2::new(columns as f32, rows as f32) * settings.cell_size;
+  transform.scale = grid.extend(1.0);
+  transform.translation = ((grid - dish) / 2.0 * Vec2::new(1.0, -1.0)).extend(DENSITY_DEPTH);
+
+  let type_colors: Vec<LinearRgba> = particle_spec
+    .materials
+    .iter()
+    .map(|handle| {
+      materials
+        .get(handle)
+        .map_or(LinearRgba::WHITE, |material| material.base_color.into())
+    })
+    .collect();
+  let type_count = type_colors.len();
+  let mut counts = vec![0.0; columns * rows * type_count];
+  let top_left = Vec2::new(-dish.x, dish.y) / 2.0;
+  for (particle_transform, interaction) in particles.iter() {
+    if interaction.0 >= type_count {
+      continue;
+    }
+    let offset = particle_transform.translation.xy() - top_left;
+    let column = ((offset.x / settings.cell_size) as usize).min(columns - 1);
+    let row = ((-offset.y / settings.cell_size) as usize).min(rows - 1);
+    counts[(row * columns + column) * type_count + interaction.0] += 1.0;
+  }
+  let counts = blur(&counts, columns, rows, type_count);
+
+  let totals: Vec<f32> = counts
+    .chunks(type_count.max(1))
+    .map(|cell| cell.iter().sum())
+    .collect();
+  let occupied = totals
+    .iter()
+    .copied()
+    .filter(|&total| total > 0.0)
+    .collect();
+  let scale = coloring::percentile(occupied, DENSITY_PERCENTILE);
+
+  let image = match images.get_mut(&overlay.image) {
+    Some(image) => image,
+    None => return,
+  };
+  let size = Extent3d {
+    width: columns as u32,
+    height: rows as u32,
+    depth_or_array_layers: 1,
+  };
+  if image.texture_descriptor.size != size {
+    image.resize(size);
+  }
+  for ((pixel, cell), &total) in image
+    .data
+    .chunks_exact_mut(4)
+    .zip(counts.chunks(type_count.max(1)))
+    .zip(totals.iter())
+  {
+    if total <= 0.0 || scale <= 0.0 {
+      pixel.copy_from_slice(&[0, 0, 0, 0]);
+      continue;
+    }
+    let mixed = cell
+      .iter()
+      .zip(type_colors.iter())
+      .fold(LinearRgba::NONE, |mixed, (&count, color)| {
+        mixed + *color * (count / total)
+      });
+    let alpha = MAX_ALPHA * (total / scale).min(1.0).sqrt();
+    pixel.copy_from_slice(
+      &Color::from(mixed.with_alpha(alpha))
+        .to_srgba()
+        .to_u8_array(),
+    );
+  }
+}
+
+/// Averages every cell with its neighbours, wrapping around the edges like the
+/// dish does, to smooth out single particles.
+fn blur(counts: &[f32], columns: usize, rows: usize, type_count: usize) -> Vec<f32> {
+  let mut blurred = vec![0.0; counts.len()];
+  for row in 0..rows {
+    for column in 0..columns {
+      let cell = (row * columns + column) * type_count;
+      for dy in [rows - 1, 0, 1] {
+        for dx in [columns - 1, 0, 1] {
+          let neighbour = (((row + dy) % rows) * columns + (column + dx) % columns) * type_count;
+          for t in 0..type_count {
+            blurred[cell + t] += counts[neighbour + t] / 9.0;
+          }
+        }
+      }
+    }
+  }
+  blurred
+}
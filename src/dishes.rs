@@ -0,0 +1,266 @@
+use bevy::prelude::*;
+use bevy::render::camera::Viewport;
+use bevy::render::view::RenderLayers;
+use bevy::ui::IsDefaultUiCamera;
+use bevy::window::PrimaryWindow;
+
+use std::collections::HashMap;
+
+use crate::args::Renderer;
+use crate::core::*;
+use crate::headless::HeadlessSim;
+use crate::loading::{self, SpecError};
+use crate::palette;
+use crate::render;
+use crate::sim;
+
+/// A dish running its own spec next to the main simulation.
+struct ExtraDish {
+  sim: HeadlessSim,
+  /// Type materials of the dish's spec.
+  materials: Vec<Handle<StandardMaterial>>,
+  /// The particles drawn in the app, by their entity in the dish's own world.
+  entities: HashMap<Entity, Entity>,
+}
+
+/// The extra dishes given with --compare. Each runs in a world of its own, so
+/// the simulation systems see only its spec, region and particles, while the
+/// app shows copies of its particles tagged with `Dish`.
+///
+/// Not a resource, as the worlds can't be shared between threads.
+#[derive(Default)]
+pub struct Dishes {
+  specs: Vec<ParticleSpec>,
+  dishes: Vec<ExtraDish>,
+}
+
+impl Dishes {
+  pub fn load(args: &ProgramArgs) -> Result<Dishes, SpecError> {
+    let specs = args
+      .compare
+      .iter()
+      .map(|path| {
+        let mut particle_spec = loading::load_spec_file(path)?;
+        palette::assign_colors(args, &mut particle_spec);
+        Ok(particle_spec)
+      })
+      .collect::<Result<_, SpecError>>()?;
+    Ok(Dishes {
+      specs,
+      dishes: vec![],
+    })
+  }
+
+  /// Number of dishes, counting the main one.
+  pub fn count(&self) -> usize {
+    1 + self.specs.len().max(self.dishes.len())
+  }
+}
+
+/// Camera of an extra dish, panned and zoomed separately from the main one
+/// unless --sync-cameras is given.
+#[derive(Component, Debug)]
+pub struct DishCamera;
+
+/// Starts the extra dishes in a region the size of the main one, and spawns
+/// their particles and cameras.
+#[allow(clippy::too_many_arguments)]
+pub fn init_dishes(
+  mut commands: Commands,
+  mut dishes: NonSendMut<Dishes>,
+  args: Res<ProgramArgs>,
+  sim_region: Res<SimRegion>,
+  mut meshes: ResMut<Assets<Mesh>>,
+  mut materials: ResMut<Assets<StandardMaterial>>,
+  main_camera: Query<(Entity, &CameraZoom), With<MainCamera>>,
+) {
+  if dishes.specs.is_empty() {
+    return;
+  }
+  let dish_size = sim_region.size();
+  let circle_mesh = meshes.add(Sphere::new(args.particle_size));
+  let seed = args.layout_seed.unwrap_or_default();
+
+  // The cameras start out at the main camera's zoom, until fit_views_to_window
+  // sees how big the viewports are.
+  let mut zoom = CameraZoom::new(render::ZOOM_BASE, 1);
+  if let Ok((entity, main_zoom)) = main_camera.get_single() {
+    zoom = CameraZoom::new(main_zoom.base, main_zoom.home_exponent);
+    // Otherwise the UI would follow the camera of the last dish.
+    commands.entity(entity).insert(IsDefaultUiCamera);
+  }
+
+  let specs = std::mem::take(&mut dishes.specs);
+  for (index, mut particle_spec) in specs.into_iter().enumerate() {
+    let dish = Dish(index + 1);
+    let layer = RenderLayers::layer(dish.0);
+    particle_spec.materials = render::create_materials(&particle_spec, &mut materials);
+    let type_materials = particle_spec.materials.clone();
+    let mut sim = HeadlessSim::new(
+      particle_spec,
+      args.num_particles,
+      dish_size.x,
+      dish_size.y,
+      &mut sim::layout_rng(seed, dish.0),
+    );
+    let mut entities = HashMap::new();
+    sim.visit_particles(|dish_entity, transform, interaction| {
+      let mut particle = commands.spawn((
+        dish,
+        *transform,
+        MeshMaterial3d(type_materials[interaction.0].clone()),
+        layer.clone(),
+      ));
+      if args.renderer == Renderer::Mesh {
+        particle.insert(Mesh3d(circle_mesh.clone()));
+      }
+      entities.insert(dish_entity, particle.id());
+    });
+    dishes.dishes.push(ExtraDish {
+      sim,
+      materials: type_materials,
+      entities,
+    });
+
+    commands.spawn((
+      dish,
+      DishCamera,
+      layer,
+      render::camera(dish.0 as isize, zoom),
+    ));
+  }
+}
+
+pub fn step_dishes(state: Res<State<SimState>>, mut dishes: NonSendMut<Dishes>) {
+  if state.get() == &SimState::Paused {
+    return;
+  }
+  for dish in dishes.dishes.iter_mut() {
+    dish.sim.step(1);
+  }
+}
+
+/// Copies the state of each extra dish onto the particles drawn for it.
+pub fn sync_dish_particles(
+  mut dishes: NonSendMut<Dishes>,
+  mut particles: Query<(&mut Transform, &mut MeshMaterial3d<StandardMaterial>), With<Dish>>,
+) {
+  for dish in dishes.dishes.iter_mut() {
+    let ExtraDish {
+      sim,
+      materials,
+      entities,
+    } = dish;
+    sim.visit_particles(|dish_entity, transform, interaction| {
+      let particle = entities
+        .get(&dish_entity)
+        .and_then(|&entity| particles.get_mut(entity).ok());
+      if let Some((mut particle_transform, mut material)) = particle {
+        *particle_transform = *transform;
+        if material.0 != materials[interaction.0] {
+          material.0 = materials[interaction.0].clone();
+        }
+      }
+    });
+  }
+}
+
+/// Splits the window into side-by-side viewports, the main dish leftmost.
+#[allow(clippy::type_complexity)]
+pub fn layout_viewports(
+  dishes: NonSend<Dishes>,
+  windows: Query<&Window, With<PrimaryWindow>>,
+  mut cameras: Query<(&mut Camera, Option<&Dish>), Or<(With<MainCamera>, With<DishCamera>)>>,
+) {
+  let count = dishes.count();
+  if count < 2 {
+    return;
+  }
+  let window = match windows.get_single() {
+    Ok(window) => window,
+    Err(_) => return,
+  };
+  let width = window.physical_width() / count as u32;
+  let height = window.physical_height();
+  for (mut camera, dish) in cameras.iter_mut() {
+    let column = dish.map_or(0, |dish| dish.0) as u32;
+    let viewport = Viewport {
+      physical_position: UVec2::new(column * width, 0),
+      physical_size: UVec2::new(width.max(1), height.max(1)),
+      ..Default::default()
+    };
+    if camera
+      .viewport
+      .as_ref()
+      .map(|current| (current.physical_position, current.physical_size))
+      != Some((viewport.physical_position, viewport.physical_size))
+    {
+      camera.viewport = Some(viewport);
+    }
+  }
+}
+
+/// Fits the views to the window when it opens and whenever it changes size.
+/// The home zoom of every camera is the one at which its whole dish fits in its
+/// viewport, with a step to spare, and cameras still at their home zoom follow
+/// it.
+#[allow(clippy::type_complexity)]
+pub fn fit_views_to_window(
+  dishes: NonSend<Dishes>,
+  sim_region: Res<SimRegion>,
+  windows: Query<&Window, With<PrimaryWindow>>,
+  mut cameras: Query<(&mut CameraZoom, &mut Projection)>,
+  mut last_size: Local<Option<Vec2>>,
+) {
+  let window = match windows.get_single() {
+    Ok(window) => window,
+    Err(_) => return,
+  };
+  let size = window.size();
+  if *last_size == Some(size) {
+    return;
+  }
+  let opening = last_size.is_none();
+  *last_size = Some(size);
+
+  let viewport = size / Vec2::new(dishes.count() as f32, 1.0);
+  for (mut zoom, mut projection) in cameras.iter_mut() {
+    let old_home = zoom.home_exponent;
+    let home = render::fit_zoom_exponent(zoom.base, sim_region.size(), viewport) + 1;
+    zoom.home_exponent = home;
+    if zoom.exponent == old_home {
+      zoom.exponent = home;
+      zoom.anchor = Vec2::ZERO;
+      // Ease into the new zoom after a resize, but start out at the right one.
+      if opening {
+        render::set_projection_scale(&mut projection, zoom.scale());
+      }
+    }
+  }
+}
+
+/// Makes the dish cameras follow the main camera when the cameras are
+/// synchronised. Otherwise they are panned and zoomed like the main camera,
+/// by whichever viewport the cursor is over.
+#[allow(clippy::type_complexity)]
+pub fn sync_dish_cameras(
+  args: Res<ProgramArgs>,
+  main_camera: Query<(&CameraZoom, &Transform, &Projection), With<MainCamera>>,
+  mut cameras: Query<
+    (&mut CameraZoom, &mut Transform, &mut Projection),
+    (With<DishCamera>, Without<MainCamera>),
+  >,
+) {
+  if !args.sync_cameras {
+    return;
+  }
+  let (main_zoom, main_transform, main_projection) = match main_camera.get_single() {
+    Ok(main_camera) => main_camera,
+    Err(_) => return,
+  };
+  for (mut zoom, mut transform, mut projection) in cameras.iter_mut() {
+    *zoom = *main_zoom;
+    *transform = *main_transform;
+    render::set_projection_scale(&mut projection, render::projection_scale(main_projection));
+  }
+}
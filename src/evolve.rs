@@ -0,0 +1,299 @@
+use chrono::offset::Utc;
+
+use rand::Rng;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::args::{EvolveArgs, HeadlessArgs, ProgramArgs};
+use crate::core::*;
+use crate::fitness::{self, FitnessMetric};
+use crate::generate::{self, GeneratorConfig};
+use crate::headless::{self, HeadlessSim, RunError};
+use crate::loading;
+use crate::offscreen::{self, FrameRenderer};
+
+#[derive(Debug, Clone, Copy)]
+enum Mutation {
+  Perturb,
+  SwapTypes,
+  AddType,
+  RemoveType,
+}
+
+pub fn run(program_args: &ProgramArgs, evolve_args: &EvolveArgs) -> Result<(), RunError> {
+  let config = GeneratorConfig::from_args(program_args);
+  config.validate()?;
+  headless::validate_headless_args(&evolve_args.headless)?;
+  // The seed spec isn't a result of the run, so it isn't saved on its own.
+  let mut best = loading::load_or_generate_spec(program_args)?;
+  let bounds = coeff_bounds(&best, &config);
+
+  let seed = evolve_args.seed.unwrap_or_else(|| rand::thread_rng().gen());
+  let mut rng = generate::seeded_rng(seed);
+  let layout_seed = rng.gen();
+
+  let output_dir = evolve_args
+    .output_dir
+    .clone()
+    .unwrap_or_else(|| PathBuf::from(format!("evolve-{}", Utc::now().format("%F-%H-%M-%S"))));
+  fs::create_dir_all(&output_dir).map_err(|source| RunError::Io {
+    path: output_dir.clone(),
+    source,
+  })?;
+
+  let metric = fitness::metric(evolve_args.fitness);
+  let thumbnail = |generation, candidate| {
+    evolve_args
+      .thumbnails
+      .then(|| output_dir.join(format!("{}.png", candidate_name(generation, candidate))))
+  };
+  let evaluate = |spec: &ParticleSpec, thumbnail: Option<PathBuf>| {
+    evaluate(
+      spec,
+      metric.as_ref(),
+      program_args,
+      &evolve_args.headless,
+      layout_seed,
+      thumbnail.as_deref(),
+    )
+  };
+
+  let mut best_fitness = evaluate(&best, thumbnail(0, 0))?;
+  best.info.name = Some(candidate_name(0, 0));
+  best.info.description = Some(format!("initial spec; fitness {}", best_fitness));
+  loading::write_spec_file(&best, &output_dir.join(candidate_file_name(0, 0)))?;
+  println!("generation 0: fitness {}", best_fitness);
+
+  for generation in 1..=evolve_args.generations {
+    let mut generation_best: Option<(ParticleSpec, f32)> = None;
+    for candidate in 0..evolve_args.population {
+      let (mut child, mutation) = mutate(
+        &best,
+        &mut rng,
+        &config,
+        bounds,
+        evolve_args.mutation_strength,
+      );
+      let child_fitness = evaluate(&child, thumbnail(generation, candidate))?;
+      child.info.name = Some(candidate_name(generation, candidate));
+      child.info.description = Some(format!(
+        "{:?} of {}; fitness {}",
+        mutation,
+        best.info.name.as_deref().unwrap_or("unnamed spec"),
+        child_fitness
+      ));
+      child.info.created = Some(Utc::now());
+      loading::write_spec_file(
+        &child,
+        &output_dir.join(candidate_file_name(generation, candidate)),
+      )?;
+      println!(
+        "generation {} candidate {}: {:?}, fitness {}",
+        generation, candidate, mutation, child_fitness
+      );
+      if generation_best
+        .as_ref()
+        .is_none_or(|(_, fitness)| child_fitness > *fitness)
+      {
+        generation_best = Some((child, child_fitness));
+      }
+    }
+    if let Some((child, child_fitness)) = generation_best {
+      if child_fitness > best_fitness {
+        best = child;
+        best_fitness = child_fitness;
+      }
+    }
+    println!(
+      "generation {}: best {} with fitness {}",
+      generation,
+      best.info.name.as_deref().unwrap_or("unnamed spec"),
+      best_fitness
+    );
+  }
+
+  let best_path = output_dir.join("best.ron");
+  loading::write_spec_file(&best, &best_path)?;
+  println!("best spec written to {:?}", best_path);
+  Ok(())
+}
+
+fn candidate_name(generation: usize, candidate: usize) -> String {
+  format!("gen-{:03}-cand-{:02}", generation, candidate)
+}
+
+fn candidate_file_name(generation: usize, candidate: usize) -> String {
+  format!("{}.ron", candidate_name(generation, candidate))
+}
+
+/// Runs a spec headlessly and scores its final state, optionally saving a
+/// picture of it. Every candidate starts from the same particle layout, so
+/// scores are comparable.
+fn evaluate(
+  particle_spec: &ParticleSpec,
+  metric: &dyn FitnessMetric,
+  program_args: &ProgramArgs,
+  headless_args: &HeadlessArgs,
+  layout_seed: u64,
+  thumbnail: Option<&Path>,
+) -> Result<f32, RunError> {
+  let mut sim = HeadlessSim::new(
+    particle_spec.clone(),
+    program_args.num_particles,
+    headless_args.dish_width,
+    headless_args.dish_height,
+    &mut generate::seeded_rng(layout_seed),
+  );
+  sim.step(headless_args.ticks);
+  if let Some(path) = thumbnail {
+    let renderer = FrameRenderer::new(
+      particle_spec,
+      sim.sim_region().size(),
+      program_args.particle_size,
+      headless_args.image_scale,
+    );
+    offscreen::save_png(&sim.render(&renderer), path)?;
+  }
+  let snapshot = sim.snapshot();
+  Ok(metric.score(&snapshot, sim.sim_region(), &particle_spec.kernel))
+}
+
+/// The range perturbed coefficients are kept in: the generator's range, widened
+/// to take in every coefficient of the starting spec so that evolving a spec
+/// doesn't cut it down.
+fn coeff_bounds(start: &ParticleSpec, config: &GeneratorConfig) -> (f32, f32) {
+  start
+    .interactions
+    .iter()
+    .flat_map(|interaction| interaction.force_coeffs.iter())
+    .fold(
+      (config.coeff_min, config.coeff_max),
+      |(min, max), &coeff| (min.min(coeff), max.max(coeff)),
+    )
+}
+
+fn mutate(
+  parent: &ParticleSpec,
+  rng: &mut impl Rng,
+  config: &GeneratorConfig,
+  (coeff_min, coeff_max): (f32, f32),
+  strength: f32,
+) -> (ParticleSpec, Mutation) {
+  let mut child = parent.clone();
+  let type_count = child.interactions.len();
+  let roll = rng.gen::<f32>();
+  let mutation = if type_count < 2 || roll < 0.7 {
+    Mutation::Perturb
+  } else if roll < 0.8 {
+    Mutation::SwapTypes
+  } else if roll < 0.9 {
+    Mutation::AddType
+  } else {
+    Mutation::RemoveType
+  };
+
+  let range = config.coeff_max - config.coeff_min;
+  let random_coeff = |rng: &mut dyn rand::RngCore| config.coeff_min + range * rng.gen::<f32>();
+  match mutation {
+    Mutation::Perturb => {
+      for interaction in child.interactions.iter_mut() {
+        for coeff in interaction.force_coeffs.iter_mut() {
+          let delta = strength * range * (2.0 * rng.gen::<f32>() - 1.0);
+          *coeff = (*coeff + delta).clamp(coeff_min, coeff_max);
+        }
+      }
+    }
+    Mutation::SwapTypes => {
+      let a = rng.gen_range(0..type_count);
+      let b = (a + rng.gen_range(1..type_count)) % type_count;
+      child.interactions.swap(a, b);
+      for interaction in child.interactions.iter_mut() {
+        interaction.force_coeffs.swap(a, b);
+      }
+      if !child.info.type_names.is_empty() {
+        child.info.type_names.swap(a, b);
+      }
+      if !child.info.colors.is_empty() {
+        child.info.colors.swap(a, b);
+      }
+    }
+    Mutation::AddType => {
+      for interaction in child.interactions.iter_mut() {
+        interaction.force_coeffs.push(random_coeff(rng));
+      }
+      child.interactions.push(Interaction {
+        force_coeffs: (0..=type_count).map(|_| random_coeff(rng)).collect(),
+      });
+      child.info.type_names.clear();
+      child.info.colors.clear();
+    }
+    Mutation::RemoveType => {
+      let removed = rng.gen_range(0..type_count);
+      child.interactions.remove(removed);
+      for interaction in child.interactions.iter_mut() {
+        interaction.force_coeffs.remove(removed);
+      }
+      if !child.info.type_names.is_empty() {
+        child.info.type_names.remove(removed);
+      }
+      if !child.info.colors.is_empty() {
+        child.info.colors.remove(removed);
+      }
+    }
+  }
+  child.info.seed = None;
+  (child, mutation)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::args::Generator;
+
+  fn config() -> GeneratorConfig {
+    GeneratorConfig {
+      kind: Generator::Uniform,
+      coeff_min: -500.0,
+      coeff_max: 500.0,
+      sparsity: 0.5,
+      blocks: 2,
+    }
+  }
+
+  fn spec(coeffs: &[&[f32]]) -> ParticleSpec {
+    ParticleSpec {
+      interactions: coeffs
+        .iter()
+        .map(|row| Interaction {
+          force_coeffs: row.to_vec(),
+        })
+        .collect(),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn bounds_widen_to_the_starting_spec() {
+    assert_eq!(coeff_bounds(&spec(&[&[10.0]]), &config()), (-500.0, 500.0));
+    assert_eq!(
+      coeff_bounds(&spec(&[&[900.0, 0.0], &[-2000.0, 1.0]]), &config()),
+      (-2000.0, 900.0)
+    );
+  }
+
+  #[test]
+  fn perturbing_keeps_coefficients_outside_the_generator_range() {
+    // With one type, every mutation is a perturbation.
+    let start = spec(&[&[900.0]]);
+    let bounds = coeff_bounds(&start, &config());
+    let mut rng = generate::seeded_rng(1);
+    let (child, _) = mutate(&start, &mut rng, &config(), bounds, 0.0);
+    assert_eq!(child.interactions[0].force_coeffs, vec![900.0]);
+    for _ in 0..100 {
+      let (child, _) = mutate(&start, &mut rng, &config(), bounds, 0.5);
+      let coeff = child.interactions[0].force_coeffs[0];
+      assert!((-500.0..=900.0).contains(&coeff), "{}", coeff);
+    }
+  }
+}
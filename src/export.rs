@@ -0,0 +1,58 @@
+use chrono::offset::Utc;
+
+use rand::Rng;
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::args::{ExportArgs, ProgramArgs};
+use crate::generate;
+use crate::headless::{self, HeadlessSim, RunError};
+use crate::loading;
+use crate::offscreen::{self, FrameRenderer};
+
+pub fn run(program_args: &ProgramArgs, export_args: &ExportArgs) -> Result<(), RunError> {
+  headless::validate_headless_args(&export_args.headless)?;
+  let particle_spec = loading::load_or_generate_spec(program_args)?;
+  let seed = export_args.seed.unwrap_or_else(|| rand::thread_rng().gen());
+
+  let output_dir = export_args
+    .output_dir
+    .clone()
+    .unwrap_or_else(|| PathBuf::from(format!("export-{}", Utc::now().format("%F-%H-%M-%S"))));
+  fs::create_dir_all(&output_dir).map_err(|source| RunError::Io {
+    path: output_dir.clone(),
+    source,
+  })?;
+
+  let headless = &export_args.headless;
+  let mut sim = HeadlessSim::new(
+    particle_spec.clone(),
+    program_args.num_particles,
+    headless.dish_width,
+    headless.dish_height,
+    &mut generate::seeded_rng(seed),
+  );
+  let renderer = FrameRenderer::new(
+    &particle_spec,
+    sim.sim_region().size(),
+    program_args.particle_size,
+    headless.image_scale,
+  );
+  let interval = export_args.frame_interval.max(1);
+  let mut tick = 0;
+  let mut frame = 0;
+  loop {
+    let path = output_dir.join(format!("frame-{:06}.png", frame));
+    offscreen::save_png(&sim.render(&renderer), &path)?;
+    frame += 1;
+    if tick >= headless.ticks {
+      break;
+    }
+    let ticks = interval.min(headless.ticks - tick);
+    sim.step(ticks);
+    tick += ticks;
+  }
+  println!("{} frames written to {:?}", frame, output_dir);
+  Ok(())
+}
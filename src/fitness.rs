@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use crate::args::Fitness;
+use crate::core::*;
+use crate::observables::{neighbour_counts, Snapshot};
+
+/// Scores a simulation state; higher is more interesting.
+pub trait FitnessMetric {
+  fn score(&self, snapshot: &Snapshot, sim_region: &SimRegion, kernel: &KernelParams) -> f32;
+}
+
+pub fn metric(fitness: Fitness) -> Box<dyn FitnessMetric> {
+  match fitness {
+    Fitness::Clustering => Box::new(Clustering),
+    Fitness::Motion => Box::new(Motion),
+    Fitness::Diversity => Box::new(Diversity),
+  }
+}
+
+/// Index of dispersion (variance over mean) of neighbour counts. Particles
+/// spread uniformly at random score about 1, clumped ones score higher.
+pub struct Clustering;
+
+impl FitnessMetric for Clustering {
+  fn score(&self, snapshot: &Snapshot, sim_region: &SimRegion, kernel: &KernelParams) -> f32 {
+    let counts = neighbour_counts(snapshot, sim_region, kernel.interaction_radius);
+    if counts.is_empty() {
+      return 0.0;
+    }
+    let n = counts.len() as f32;
+    let mean = counts.iter().sum::<usize>() as f32 / n;
+    if mean == 0.0 {
+      return 0.0;
+    }
+    let variance = counts
+      .iter()
+      .map(|&count| (count as f32 - mean).powi(2))
+      .sum::<f32>()
+      / n;
+    variance / mean
+  }
+}
+
+/// Mean particle speed, in units per second.
+pub struct Motion;
+
+impl FitnessMetric for Motion {
+  fn score(&self, snapshot: &Snapshot, _: &SimRegion, _: &KernelParams) -> f32 {
+    if snapshot.velocities.is_empty() {
+      return 0.0;
+    }
+    let total: f32 = snapshot.velocities.iter().map(|v| v.length()).sum();
+    total / snapshot.velocities.len() as f32 / DELTA_TIME as f32
+  }
+}
+
+/// Shannon entropy, in bits, of the distribution of neighbour counts. States
+/// mixing dense structures, thin filaments and empty space score highest.
+pub struct Diversity;
+
+impl FitnessMetric for Diversity {
+  fn score(&self, snapshot: &Snapshot, sim_region: &SimRegion, kernel: &KernelParams) -> f32 {
+    let counts = neighbour_counts(snapshot, sim_region, kernel.interaction_radius);
+    let mut histogram: HashMap<usize, usize> = HashMap::new();
+    for count in counts.iter() {
+      *histogram.entry(*count).or_default() += 1;
+    }
+    let n = counts.len() as f32;
+    histogram
+      .values()
+      .map(|&frequency| {
+        let p = frequency as f32 / n;
+        -p * p.log2()
+      })
+      .sum()
+  }
+}
@@ -0,0 +1,85 @@
+use bevy::color::palettes::css::{LIME, RED, WHITE};
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+
+use crate::args::ForceVectors;
+use crate::core::*;
+use crate::sim::SelectedParticle;
+
+/// Arrow length per unit of acceleration.
+const ARROW_SCALE: f32 = 0.02;
+/// Arrows are drawn in front of the particles.
+const ARROW_DEPTH: f32 = 2.0;
+
+#[derive(Resource, Debug)]
+pub struct ForceOverlay {
+  pub vectors: Option<ForceVectors>,
+}
+
+impl ForceOverlay {
+  pub fn from_args(args: &ProgramArgs) -> ForceOverlay {
+    ForceOverlay {
+      vectors: args.force_vectors,
+    }
+  }
+
+  /// Cycles from off through the selected particle only to all particles.
+  pub fn next(&mut self) {
+    self.vectors = match self.vectors {
+      None => Some(ForceVectors::Selected),
+      Some(ForceVectors::Selected) => Some(ForceVectors::All),
+      Some(ForceVectors::All) => None,
+    };
+  }
+}
+
+/// Draws the net accelerations from the last tick, and the pairwise ones on
+/// the selected particle captured by `sim::compute_forces` at the same time.
+/// The pairwise arrows sum to the net one, less friction; as they're drawn at
+/// the particles' current positions, they trail the particles by a tick.
+pub fn draw_force_vectors(
+  overlay: Res<ForceOverlay>,
+  selected: Res<SelectedParticle>,
+  particles: Query<(&Transform, &Acceleration)>,
+  mut gizmos: Gizmos,
+) {
+  let vectors = match overlay.vectors {
+    Some(vectors) => vectors,
+    None => return,
+  };
+  let mut draw_arrow = |from: Vec2, acceleration: Vec2, color: Srgba| {
+    gizmos.arrow(
+      from.extend(ARROW_DEPTH),
+      (from + acceleration * ARROW_SCALE).extend(ARROW_DEPTH),
+      color,
+    );
+  };
+
+  if vectors == ForceVectors::All {
+    for (transform, acceleration) in particles.iter() {
+      draw_arrow(transform.translation.xy(), acceleration.0, WHITE);
+    }
+  }
+
+  let (transform, acceleration) = match selected.id.and_then(|id| particles.get(id).ok()) {
+    Some(particle) => particle,
+    None => return,
+  };
+  let position = transform.translation.xy();
+  if vectors == ForceVectors::Selected {
+    draw_arrow(position, acceleration.0, WHITE);
+  }
+  for pair in selected.pair_forces.iter() {
+    let color = if pair.attracting { LIME } else { RED };
+    draw_arrow(position, pair.acceleration, color);
+    if vectors == ForceVectors::Selected {
+      if let Ok((other_transform, other_acceleration)) = particles.get(pair.other) {
+        draw_arrow(
+          other_transform.translation.xy(),
+          other_acceleration.0,
+          WHITE,
+        );
+      }
+    }
+  }
+}
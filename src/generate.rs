@@ -0,0 +1,303 @@
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::args::{Generator, ProgramArgs};
+use crate::core;
+use crate::loading::SpecError;
+
+/// Parameters shared by all coefficient generators.
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+  pub kind: Generator,
+  pub coeff_min: f32,
+  pub coeff_max: f32,
+  pub sparsity: f64,
+  pub blocks: usize,
+}
+
+impl GeneratorConfig {
+  pub fn from_args(args: &ProgramArgs) -> GeneratorConfig {
+    GeneratorConfig {
+      kind: args.generator,
+      coeff_min: args.coeff_min,
+      coeff_max: args.coeff_max,
+      sparsity: args.sparsity,
+      blocks: args.blocks,
+    }
+  }
+
+  pub fn validate(&self) -> Result<(), SpecError> {
+    if !self.coeff_min.is_finite() || !self.coeff_max.is_finite() {
+      return Err(SpecError::GeneratorParam {
+        name: "coeff-min/coeff-max",
+        reason: "must be finite",
+      });
+    }
+    if self.coeff_min > self.coeff_max {
+      return Err(SpecError::GeneratorParam {
+        name: "coeff-min",
+        reason: "must not exceed coeff-max",
+      });
+    }
+    if !(0.0..=1.0).contains(&self.sparsity) {
+      return Err(SpecError::GeneratorParam {
+        name: "sparsity",
+        reason: "must be between 0 and 1",
+      });
+    }
+    if self.blocks == 0 {
+      return Err(SpecError::GeneratorParam {
+        name: "blocks",
+        reason: "must be at least 1",
+      });
+    }
+    Ok(())
+  }
+
+  fn uniform(&self, rng: &mut impl Rng) -> f32 {
+    self.coeff_min + (self.coeff_max - self.coeff_min) * rng.gen::<f32>()
+  }
+
+  fn uniform_within(&self, rng: &mut impl Rng, low: f32, high: f32) -> f32 {
+    let min = self.coeff_min + (self.coeff_max - self.coeff_min) * low;
+    let max = self.coeff_min + (self.coeff_max - self.coeff_min) * high;
+    min + (max - min) * rng.gen::<f32>()
+  }
+
+  fn gaussian(&self, rng: &mut impl Rng) -> f32 {
+    let mean = 0.5 * (self.coeff_min + self.coeff_max);
+    let std_dev = (self.coeff_max - self.coeff_min) / 6.0;
+    // Box-Muller transform; 1 - u keeps the logarithm's argument nonzero.
+    let u1 = 1.0 - rng.gen::<f32>();
+    let u2 = rng.gen::<f32>();
+    let z = (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos();
+    mean + std_dev * z
+  }
+}
+
+pub fn seeded_rng(seed: u64) -> SmallRng {
+  let b = seed.to_le_bytes();
+  let more_bytes = [
+    b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+    b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+  ];
+  SmallRng::from_seed(more_bytes)
+}
+
+pub fn generate_particle_spec(
+  rng: &mut impl Rng,
+  type_count: usize,
+  config: &GeneratorConfig,
+) -> core::ParticleSpec {
+  let interactions = generate_matrix(rng, type_count, config)
+    .into_iter()
+    .map(|force_coeffs| core::Interaction { force_coeffs })
+    .collect();
+  core::ParticleSpec {
+    interactions,
+    info: core::SpecInfo {
+      description: Some(format!("{:?} generator", config.kind)),
+      ..Default::default()
+    },
+    ..Default::default()
+  }
+}
+
+/// Builds a `type_count` x `type_count` matrix, where `matrix[i][j]` is the
+/// force exerted by particles of type `i` on particles of type `j` (positive
+/// values attract).
+#[allow(clippy::needless_range_loop)]
+fn generate_matrix(
+  rng: &mut impl Rng,
+  type_count: usize,
+  config: &GeneratorConfig,
+) -> Vec<Vec<f32>> {
+  let mut matrix = vec![vec![0.0; type_count]; type_count];
+  match config.kind {
+    Generator::Uniform => {
+      for row in matrix.iter_mut() {
+        for coeff in row.iter_mut() {
+          *coeff = config.uniform(rng);
+        }
+      }
+    }
+    Generator::Symmetric => {
+      for i in 0..type_count {
+        for j in i..type_count {
+          let coeff = config.uniform(rng);
+          matrix[i][j] = coeff;
+          matrix[j][i] = coeff;
+        }
+      }
+    }
+    Generator::Antisymmetric => {
+      for i in 0..type_count {
+        matrix[i][i] = config.uniform(rng);
+        for j in i + 1..type_count {
+          let coeff = config.uniform(rng);
+          matrix[i][j] = coeff;
+          matrix[j][i] = -coeff;
+        }
+      }
+    }
+    Generator::Sparse => {
+      for row in matrix.iter_mut() {
+        for coeff in row.iter_mut() {
+          let value = config.uniform(rng);
+          if !rng.gen_bool(config.sparsity) {
+            *coeff = value;
+          }
+        }
+      }
+    }
+    Generator::Gaussian => {
+      for row in matrix.iter_mut() {
+        for coeff in row.iter_mut() {
+          *coeff = config.gaussian(rng);
+        }
+      }
+    }
+    Generator::PredatorPrey => {
+      // Weak background noise around the middle of the range, with every type
+      // chasing the next one and fleeing from the previous one.
+      for row in matrix.iter_mut() {
+        for coeff in row.iter_mut() {
+          *coeff = config.uniform_within(rng, 0.4, 0.6);
+        }
+      }
+      // With only two types the cycle would overwrite itself, so there's just
+      // one chase.
+      let chases = if type_count == 2 { 1 } else { type_count };
+      if type_count > 1 {
+        for predator in 0..chases {
+          let prey = (predator + 1) % type_count;
+          matrix[prey][predator] = config.coeff_max;
+          matrix[predator][prey] = config.coeff_min;
+        }
+      }
+    }
+    Generator::Blocks => {
+      // Types in the same block attract each other with coefficients from the
+      // upper half of the range, types in different blocks use the lower half.
+      let block_of = |t: usize| t * config.blocks / type_count;
+      for (i, row) in matrix.iter_mut().enumerate() {
+        for (j, coeff) in row.iter_mut().enumerate() {
+          *coeff = if block_of(i) == block_of(j) {
+            config.uniform_within(rng, 0.5, 1.0)
+          } else {
+            config.uniform_within(rng, 0.0, 0.5)
+          };
+        }
+      }
+    }
+  }
+  matrix
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(kind: Generator) -> GeneratorConfig {
+    GeneratorConfig {
+      kind,
+      coeff_min: -500.0,
+      coeff_max: 500.0,
+      sparsity: 0.5,
+      blocks: 2,
+    }
+  }
+
+  /// A label, a change to the default config, and the parameter it should be
+  /// rejected for.
+  type ValidateCase = (&'static str, fn(&mut GeneratorConfig), Option<&'static str>);
+
+  #[test]
+  fn validate_rejects_bad_parameters() {
+    let cases: [ValidateCase; 9] = [
+      ("defaults", |_| {}, None),
+      ("equal bounds", |c| c.coeff_max = c.coeff_min, None),
+      (
+        "inverted bounds",
+        |c| c.coeff_min = 600.0,
+        Some("coeff-min"),
+      ),
+      (
+        "infinite bound",
+        |c| c.coeff_max = f32::INFINITY,
+        Some("coeff-min/coeff-max"),
+      ),
+      (
+        "NaN bound",
+        |c| c.coeff_min = f32::NAN,
+        Some("coeff-min/coeff-max"),
+      ),
+      ("sparsity of 1", |c| c.sparsity = 1.0, None),
+      ("negative sparsity", |c| c.sparsity = -0.1, Some("sparsity")),
+      ("sparsity above 1", |c| c.sparsity = 1.5, Some("sparsity")),
+      ("no blocks", |c| c.blocks = 0, Some("blocks")),
+    ];
+    for (label, tweak, expected) in cases {
+      let mut config = config(Generator::Uniform);
+      tweak(&mut config);
+      let rejected = match config.validate() {
+        Ok(()) => None,
+        Err(SpecError::GeneratorParam { name, .. }) => Some(name),
+        Err(err) => panic!("{}: unexpected error {}", label, err),
+      };
+      assert_eq!(rejected, expected, "{}", label);
+    }
+  }
+
+  #[test]
+  fn matrices_have_the_generator_shape() {
+    let type_count = 5;
+    let matrix = |kind| generate_matrix(&mut seeded_rng(7), type_count, &config(kind));
+
+    let symmetric = matrix(Generator::Symmetric);
+    let antisymmetric = matrix(Generator::Antisymmetric);
+    for i in 0..type_count {
+      for j in 0..type_count {
+        assert_eq!(symmetric[i][j], symmetric[j][i]);
+        if i != j {
+          assert_eq!(antisymmetric[i][j], -antisymmetric[j][i]);
+        }
+      }
+    }
+    for row in matrix(Generator::Uniform) {
+      assert!(row.iter().all(|coeff| (-500.0..=500.0).contains(coeff)));
+    }
+  }
+
+  #[test]
+  fn sparsity_extremes() {
+    let mut all_zero = config(Generator::Sparse);
+    all_zero.sparsity = 1.0;
+    let matrix = generate_matrix(&mut seeded_rng(1), 4, &all_zero);
+    assert!(matrix.iter().flatten().all(|&coeff| coeff == 0.0));
+
+    let mut none_zero = config(Generator::Sparse);
+    none_zero.sparsity = 0.0;
+    let matrix = generate_matrix(&mut seeded_rng(1), 4, &none_zero);
+    assert!(matrix.iter().flatten().all(|&coeff| coeff != 0.0));
+  }
+
+  #[test]
+  fn more_blocks_than_types_gives_each_type_its_own_block() {
+    let mut config = config(Generator::Blocks);
+    config.blocks = 10;
+    let matrix = generate_matrix(&mut seeded_rng(3), 3, &config);
+    for (i, row) in matrix.iter().enumerate() {
+      for (j, &coeff) in row.iter().enumerate() {
+        // Same block draws from the upper half of the range, i.e. >= 0 here.
+        assert_eq!(coeff >= 0.0, i == j, "({}, {}) = {}", i, j, coeff);
+      }
+    }
+  }
+
+  #[test]
+  fn predator_prey_with_one_type_has_no_chase() {
+    let matrix = generate_matrix(&mut seeded_rng(5), 1, &config(Generator::PredatorPrey));
+    assert!((-100.0..=100.0).contains(&matrix[0][0]));
+  }
+}
@@ -0,0 +1,174 @@
+use bevy::prelude::*;
+use image::RgbaImage;
+use rand::Rng;
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+use crate::args::HeadlessArgs;
+use crate::core::*;
+use crate::loading::SpecError;
+use crate::observables::{Observables, Snapshot};
+use crate::offscreen::FrameRenderer;
+use crate::sim;
+
+/// Errors of the subcommands running without a window.
+#[derive(Debug)]
+pub enum RunError {
+  Spec(SpecError),
+  Io {
+    path: PathBuf,
+    source: std::io::Error,
+  },
+  Parse {
+    path: PathBuf,
+    source: ron::error::SpannedError,
+  },
+  Image {
+    path: PathBuf,
+    source: image::ImageError,
+  },
+  SweepParam {
+    name: &'static str,
+    reason: &'static str,
+  },
+  HeadlessParam {
+    name: &'static str,
+    reason: &'static str,
+  },
+}
+
+impl From<SpecError> for RunError {
+  fn from(err: SpecError) -> RunError {
+    RunError::Spec(err)
+  }
+}
+
+impl Display for RunError {
+  fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+    match self {
+      RunError::Spec(err) => err.fmt(f),
+      RunError::Io { path, source } => write!(f, "cannot access {:?}: {}", path, source),
+      RunError::Parse { path, source } => write!(
+        f,
+        "{}:{}:{}: {}",
+        path.display(),
+        source.position.line,
+        source.position.col,
+        source.code
+      ),
+      RunError::Image { path, source } => write!(f, "failed to write {:?}: {}", path, source),
+      RunError::SweepParam { name, reason } => write!(f, "sweep field {}: {}", name, reason),
+      RunError::HeadlessParam { name, reason } => write!(f, "--{}: {}", name, reason),
+    }
+  }
+}
+
+impl Error for RunError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      RunError::Spec(err) => Some(err),
+      RunError::Io { source, .. } => Some(source),
+      RunError::Parse { source, .. } => Some(source),
+      RunError::Image { source, .. } => Some(source),
+      _ => None,
+    }
+  }
+}
+
+/// Checks the dish and image sizes shared by the headless subcommands.
+pub fn validate_headless_args(args: &HeadlessArgs) -> Result<(), RunError> {
+  let checks = [
+    ("dish-width", args.dish_width),
+    ("dish-height", args.dish_height),
+    ("image-scale", args.image_scale),
+  ];
+  for (name, value) in checks {
+    if !(value.is_finite() && value > 0.0) {
+      return Err(RunError::HeadlessParam {
+        name,
+        reason: "must be a positive number",
+      });
+    }
+  }
+  Ok(())
+}
+
+/// A simulation running without a window, stepped manually.
+///
+/// This uses the same systems as the interactive app, so results carry over
+/// once a spec is opened in the viewer.
+pub struct HeadlessSim {
+  app: App,
+}
+
+impl HeadlessSim {
+  pub fn new(
+    particle_spec: ParticleSpec,
+    num_particles: usize,
+    width: f32,
+    height: f32,
+    rng: &mut impl Rng,
+  ) -> HeadlessSim {
+    let mut app = App::new();
+    app
+      .add_plugins(TaskPoolPlugin::default())
+      .insert_resource(State::new(SimState::Running))
+      .add_systems(FixedUpdate, sim::step_systems());
+
+    let world = app.world_mut();
+    let type_count = particle_spec.interactions.len();
+    let mut sim_region = SimRegion::new(width, height, particle_spec.kernel.interaction_radius);
+    for _ in 0..num_particles {
+      let (bundle, transform) = sim::random_particle(rng, type_count, width, height);
+      let particle = world.spawn((bundle, transform)).id();
+      sim_region.insert_entity(particle, transform.translation.x, transform.translation.y);
+    }
+    world.insert_resource(sim_region);
+    world.insert_resource(particle_spec);
+    HeadlessSim { app }
+  }
+
+  pub fn step(&mut self, ticks: usize) {
+    for _ in 0..ticks {
+      self.app.world_mut().run_schedule(FixedUpdate);
+    }
+  }
+
+  pub fn snapshot(&mut self) -> Snapshot {
+    let world = self.app.world_mut();
+    let mut query = world.query::<(Entity, &Transform, &LastPosition, &InteractionId)>();
+    Snapshot::capture(query.iter(world), world.resource::<SimRegion>())
+  }
+
+  pub fn observables(&mut self) -> Observables {
+    let snapshot = self.snapshot();
+    let world = self.app.world();
+    let particle_spec = world.resource::<ParticleSpec>();
+    Observables::compute(
+      &snapshot,
+      world.resource::<SimRegion>(),
+      &particle_spec.kernel,
+      particle_spec.interactions.len(),
+    )
+  }
+
+  pub fn sim_region(&self) -> &SimRegion {
+    self.app.world().resource::<SimRegion>()
+  }
+
+  pub fn visit_particles(&mut self, mut visit: impl FnMut(Entity, &Transform, &InteractionId)) {
+    let world = self.app.world_mut();
+    let mut query = world.query::<(Entity, &Transform, &InteractionId)>();
+    for (entity, transform, interaction) in query.iter(world) {
+      visit(entity, transform, interaction);
+    }
+  }
+
+  pub fn render(&mut self, renderer: &FrameRenderer) -> RgbaImage {
+    let world = self.app.world_mut();
+    let mut query = world.query::<(&Transform, &InteractionId)>();
+    renderer.render(query.iter(world))
+  }
+}
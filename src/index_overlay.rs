@@ -0,0 +1,198 @@
+use bevy::color::palettes::css::{GRAY, LIME, ORANGE, RED, WHITE, YELLOW};
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+
+use crate::core::*;
+use crate::sim::SelectedParticle;
+
+/// The overlay is drawn just in front of the particles.
+const OVERLAY_DEPTH: f32 = 1.0;
+
+#[derive(Resource, Debug)]
+pub struct IndexOverlay {
+  pub enabled: bool,
+}
+
+impl IndexOverlay {
+  pub fn from_args(args: &ProgramArgs) -> IndexOverlay {
+    IndexOverlay {
+      enabled: args.index_overlay,
+    }
+  }
+}
+
+/// Occupancy count of one bucket, drawn over its centre.
+#[derive(Component)]
+pub struct BucketLabel;
+
+/// Parent of the bucket labels.
+#[derive(Component)]
+pub struct BucketLabels;
+
+pub fn init_index_overlay(mut commands: Commands) {
+  commands.spawn((
+    BucketLabels,
+    Visibility::Hidden,
+    Node {
+      position_type: PositionType::Absolute,
+      width: Val::Percent(100.0),
+      height: Val::Percent(100.0),
+      ..Default::default()
+    },
+  ));
+}
+
+/// Draws the bucket grid and, for the selected particle, the buckets
+/// `SimRegion::get_entities` searches and the kernel radii.
+pub fn draw_index_overlay(
+  overlay: Res<IndexOverlay>,
+  sim_region: Res<SimRegion>,
+  particle_spec: Res<ParticleSpec>,
+  selected: Res<SelectedParticle>,
+  particles: Query<&Transform, With<InteractionId>>,
+  mut gizmos: Gizmos,
+) {
+  if !overlay.enabled {
+    return;
+  }
+  let granularity = sim_region.granularity;
+  let half = sim_region.size() / 2.0;
+  let grid_color = GRAY.with_alpha(0.4);
+  // Bucket (i, j) spans (i ± 0.5, j ± 0.5) times the granularity.
+  let (left, bottom) = sim_region.bucket_coords(-half.x, -half.y);
+  let (right, top) = sim_region.bucket_coords(half.x, half.y);
+  for i in left..=right + 1 {
+    let x = ((i as f32 - 0.5) * granularity).clamp(-half.x, half.x);
+    gizmos.line(
+      Vec3::new(x, -half.y, OVERLAY_DEPTH),
+      Vec3::new(x, half.y, OVERLAY_DEPTH),
+      grid_color,
+    );
+  }
+  for j in bottom..=top + 1 {
+    let y = ((j as f32 - 0.5) * granularity).clamp(-half.y, half.y);
+    gizmos.line(
+      Vec3::new(-half.x, y, OVERLAY_DEPTH),
+      Vec3::new(half.x, y, OVERLAY_DEPTH),
+      grid_color,
+    );
+  }
+
+  let transform = match selected.id.and_then(|id| particles.get(id).ok()) {
+    Some(transform) => transform,
+    None => return,
+  };
+  let position = transform.translation.xy();
+  let (ix, iy) = sim_region.bucket_coords(position.x, position.y);
+  let bucket_size = Vec2::splat(granularity);
+  for x in ix - 1..=ix + 1 {
+    for y in iy - 1..=iy + 1 {
+      for bucket in sim_region.get_wrapped_buckets(x, y) {
+        // Copies across the edge look up the entities on the other side.
+        let color = if bucket == (x, y) { LIME } else { ORANGE };
+        let centre = Vec2::new(bucket.0 as f32, bucket.1 as f32) * granularity;
+        gizmos.rect(
+          Isometry3d::from_translation(centre.extend(OVERLAY_DEPTH)),
+          bucket_size * 0.96,
+          color,
+        );
+      }
+    }
+  }
+  let kernel = &particle_spec.kernel;
+  for (radius, color) in [
+    (kernel.repulsion_radius, RED),
+    (kernel.peak_distance, YELLOW),
+    (kernel.interaction_radius, WHITE),
+  ] {
+    gizmos.circle(
+      Isometry3d::from_translation(position.extend(OVERLAY_DEPTH)),
+      radius,
+      color,
+    );
+  }
+}
+
+type BucketLabelNodes<'w, 's> = Query<
+  'w,
+  's,
+  (Entity, &'static mut Node, &'static mut Visibility),
+  (With<BucketLabel>, Without<BucketLabels>),
+>;
+
+/// Keeps one label per occupied bucket on screen, reusing label entities
+/// between frames.
+#[allow(clippy::too_many_arguments)]
+pub fn update_bucket_labels(
+  mut commands: Commands,
+  overlay: Res<IndexOverlay>,
+  sim_region: Res<SimRegion>,
+  asset_server: Res<AssetServer>,
+  camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+  mut container: Query<(Entity, &mut Visibility), With<BucketLabels>>,
+  mut labels: BucketLabelNodes,
+  mut writer: TextUiWriter,
+) {
+  let (container, mut container_visibility) = match container.get_single_mut() {
+    Ok(container) => container,
+    Err(_) => return,
+  };
+  if !overlay.enabled {
+    *container_visibility = Visibility::Hidden;
+    return;
+  }
+  *container_visibility = Visibility::Inherited;
+  let (camera, camera_transform) = match camera.get_single() {
+    Ok(camera) => camera,
+    Err(_) => return,
+  };
+  let viewport_size = camera.logical_viewport_size().unwrap_or_default();
+
+  let counts = sim_region
+    .index
+    .iter()
+    .filter(|(_, entities)| !entities.is_empty())
+    .filter_map(|(&(ix, iy), entities)| {
+      let centre = Vec3::new(ix as f32, iy as f32, 0.0) * sim_region.granularity;
+      camera
+        .world_to_viewport(camera_transform, centre)
+        .ok()
+        .filter(|viewport| viewport.cmpge(Vec2::ZERO).all() && viewport.cmplt(viewport_size).all())
+        .map(|viewport| (viewport, entities.len()))
+    });
+  let mut labels = labels.iter_mut();
+  for (viewport, count) in counts {
+    match labels.next() {
+      Some((entity, mut node, mut visibility)) => {
+        node.left = Val::Px(viewport.x);
+        node.top = Val::Px(viewport.y);
+        *visibility = Visibility::Inherited;
+        *writer.text(entity, 0) = count.to_string();
+      }
+      None => {
+        let label = commands
+          .spawn((
+            BucketLabel,
+            Text::new(count.to_string()),
+            TextFont {
+              font: asset_server.load("FiraMono-Regular.ttf"),
+              font_size: 10.0,
+              ..Default::default()
+            },
+            TextColor(GRAY.into()),
+            Node {
+              position_type: PositionType::Absolute,
+              left: Val::Px(viewport.x),
+              top: Val::Px(viewport.y),
+              ..Default::default()
+            },
+          ))
+          .id();
+        commands.entity(container).add_child(label);
+      }
+    }
+  }
+  for (_, _, mut visibility) in labels {
+    *visibility = Visibility::Hidden;
+  }
+}
@@ -0,0 +1,456 @@
+use bevy::asset::load_internal_asset;
+use bevy::core_pipeline::core_3d::Transparent3d;
+use bevy::ecs::query::QueryItem;
+use bevy::ecs::system::lifetimeless::{Read, SRes};
+use bevy::ecs::system::SystemParamItem;
+use bevy::pbr::{
+  MeshPipeline, MeshPipelineKey, RenderMeshInstances, SetMeshBindGroup, SetMeshViewBindGroup,
+};
+use bevy::prelude::*;
+use bevy::render::extract_component::{ExtractComponent, ExtractComponentPlugin};
+use bevy::render::mesh::allocator::MeshAllocator;
+use bevy::render::mesh::{MeshVertexBufferLayoutRef, RenderMesh, RenderMeshBufferInfo};
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_phase::{
+  AddRenderCommand, DrawFunctions, PhaseItem, PhaseItemExtraIndex, RenderCommand,
+  RenderCommandResult, SetItemPipeline, TrackedRenderPass, ViewSortedRenderPhases,
+};
+use bevy::render::render_resource::*;
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use bevy::render::sync_world::MainEntity;
+use bevy::render::view::{ExtractedView, NoFrustumCulling, RenderLayers};
+use bevy::render::{Render, RenderApp, RenderSet};
+use bytemuck::{Pod, Zeroable};
+
+use crate::coloring;
+use crate::core::*;
+use crate::sim::SelectedParticle;
+use crate::tiling::TilingSettings;
+
+const PARTICLE_SHADER_HANDLE: Handle<Shader> =
+  Handle::weak_from_u128(0x5f0e_8a51_c1d2_4b7e_9a3c_6d14_e2b7_0c39);
+
+/// Marker flags understood by the shader.
+const SELECTED: f32 = 1.0;
+const HIGHLIGHTED: f32 = 2.0;
+
+/// Draws every particle as a quad from a single instance buffer, rather than
+/// as a mesh entity of its own.
+pub struct InstancedRenderPlugin;
+
+impl Plugin for InstancedRenderPlugin {
+  fn build(&self, app: &mut App) {
+    load_internal_asset!(
+      app,
+      PARTICLE_SHADER_HANDLE,
+      "instanced.wgsl",
+      Shader::from_wgsl
+    );
+    app
+      .add_plugins(ExtractComponentPlugin::<ParticleInstances>::default())
+      .add_systems(Startup, init_instances)
+      .add_systems(Update, update_instances.after(coloring::update_colors));
+    app
+      .sub_app_mut(RenderApp)
+      .add_render_command::<Transparent3d, DrawParticles>()
+      .init_resource::<SpecializedMeshPipelines<ParticlePipeline>>()
+      .add_systems(
+        Render,
+        (
+          queue_particles.in_set(RenderSet::QueueMeshes),
+          prepare_instance_buffers.in_set(RenderSet::PrepareResources),
+        ),
+      );
+  }
+
+  fn finish(&self, app: &mut App) {
+    app
+      .sub_app_mut(RenderApp)
+      .init_resource::<ParticlePipeline>();
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+struct ParticleInstance {
+  /// Centre, then the radii along and across the direction of motion.
+  position_size: [f32; 4],
+  /// Linear RGBA.
+  color: [f32; 4],
+  /// Rotation in radians, then the marker flags.
+  rotation_flags: [f32; 2],
+}
+
+impl ParticleInstance {
+  /// A copy of the particle drawn at `offset` from where it is.
+  fn new(
+    transform: &Transform,
+    offset: Vec2,
+    particle_size: f32,
+    color: [f32; 4],
+    flags: f32,
+  ) -> ParticleInstance {
+    ParticleInstance {
+      position_size: [
+        transform.translation.x + offset.x,
+        transform.translation.y + offset.y,
+        transform.scale.x * particle_size,
+        transform.scale.y * particle_size,
+      ],
+      color,
+      rotation_flags: [transform.rotation.to_euler(EulerRot::ZYX).0, flags],
+    }
+  }
+}
+
+/// Instance data for all particles, held by the one entity carrying the quad
+/// mesh they're drawn with.
+#[derive(Component, Default)]
+struct ParticleInstances(Vec<ParticleInstance>);
+
+impl ExtractComponent for ParticleInstances {
+  type QueryData = &'static ParticleInstances;
+  type QueryFilter = ();
+  type Out = Self;
+
+  fn extract_component(item: QueryItem<'_, Self::QueryData>) -> Option<Self> {
+    Some(ParticleInstances(item.0.clone()))
+  }
+}
+
+/// Spawns one instance buffer for the main dish, and one per extra dish on
+/// that dish's render layer.
+fn init_instances(
+  args: Res<ProgramArgs>,
+  mut commands: Commands,
+  mut meshes: ResMut<Assets<Mesh>>,
+) {
+  let quad = meshes.add(Rectangle::new(2.0, 2.0));
+  for dish in 0..=args.compare.len() {
+    let mut holder = commands.spawn((
+      Mesh3d(quad.clone()),
+      ParticleInstances::default(),
+      // The quad sits at the origin, while the particles are all over the dish.
+      NoFrustumCulling,
+    ));
+    if dish > 0 {
+      holder.insert((Dish(dish), RenderLayers::layer(dish)));
+    }
+  }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_instances(
+  args: Res<ProgramArgs>,
+  selected: Res<SelectedParticle>,
+  tiling: Res<TilingSettings>,
+  sim_region: Res<SimRegion>,
+  materials: Res<Assets<StandardMaterial>>,
+  particles: Query<(Entity, &Transform, &MeshMaterial3d<StandardMaterial>), With<InteractionId>>,
+  dish_particles: Query<(&Dish, &Transform, &MeshMaterial3d<StandardMaterial>)>,
+  mut holders: Query<(&mut ParticleInstances, Option<&Dish>)>,
+) {
+  let offsets = tiling.offsets(sim_region.size());
+  let color = |material: &MeshMaterial3d<StandardMaterial>| {
+    let color = materials
+      .get(material)
+      .map_or(Color::WHITE, |material| material.base_color);
+    LinearRgba::from(color).to_f32_array()
+  };
+  let push =
+    |instances: &mut Vec<ParticleInstance>, transform: &Transform, color: [f32; 4], flags: f32| {
+      for (index, &offset) in offsets.iter().enumerate() {
+        // Only the particle itself is marked, not its copies.
+        let flags = if index == 0 { flags } else { 0.0 };
+        instances.push(ParticleInstance::new(
+          transform,
+          offset,
+          args.particle_size,
+          color,
+          flags,
+        ));
+      }
+    };
+
+  for (mut instances, holder_dish) in holders.iter_mut() {
+    instances.0.clear();
+    match holder_dish {
+      None => {
+        for (entity, transform, material) in particles.iter() {
+          let flags = if selected.id == Some(entity) {
+            SELECTED
+          } else if selected.neighbours.contains(&entity) {
+            HIGHLIGHTED
+          } else {
+            0.0
+          };
+          push(&mut instances.0, transform, color(material), flags);
+        }
+      }
+      Some(holder_dish) => {
+        for (dish, transform, material) in dish_particles.iter() {
+          if dish == holder_dish {
+            push(&mut instances.0, transform, color(material), 0.0);
+          }
+        }
+      }
+    }
+  }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn queue_particles(
+  transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
+  particle_pipeline: Res<ParticlePipeline>,
+  mut pipelines: ResMut<SpecializedMeshPipelines<ParticlePipeline>>,
+  pipeline_cache: Res<PipelineCache>,
+  meshes: Res<RenderAssets<RenderMesh>>,
+  render_mesh_instances: Res<RenderMeshInstances>,
+  instance_holders: Query<(Entity, &MainEntity), With<ParticleInstances>>,
+  mut transparent_render_phases: ResMut<ViewSortedRenderPhases<Transparent3d>>,
+  views: Query<(Entity, &ExtractedView, &Msaa)>,
+) {
+  let draw_particles = transparent_3d_draw_functions.read().id::<DrawParticles>();
+
+  for (view_entity, view, msaa) in &views {
+    let Some(transparent_phase) = transparent_render_phases.get_mut(&view_entity) else {
+      continue;
+    };
+    let view_key = MeshPipelineKey::from_msaa_samples(msaa.samples())
+      | MeshPipelineKey::from_hdr(view.hdr)
+      | MeshPipelineKey::BLEND_ALPHA;
+    let rangefinder = view.rangefinder3d();
+    for (entity, main_entity) in &instance_holders {
+      let Some(mesh_instance) = render_mesh_instances.render_mesh_queue_data(*main_entity) else {
+        continue;
+      };
+      let Some(mesh) = meshes.get(mesh_instance.mesh_asset_id) else {
+        continue;
+      };
+      let key = view_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology());
+      let pipeline =
+        match pipelines.specialize(&pipeline_cache, &particle_pipeline, key, &mesh.layout) {
+          Ok(pipeline) => pipeline,
+          Err(err) => {
+            error!("failed to specialize the particle pipeline: {}", err);
+            continue;
+          }
+        };
+      transparent_phase.add(Transparent3d {
+        entity: (entity, *main_entity),
+        pipeline,
+        draw_function: draw_particles,
+        distance: rangefinder.distance_translation(&mesh_instance.translation),
+        batch_range: 0..1,
+        extra_index: PhaseItemExtraIndex::NONE,
+      });
+    }
+  }
+}
+
+/// GPU copy of the instance data. It is kept from frame to frame and only
+/// replaced when the particles no longer fit.
+#[derive(Component)]
+struct InstanceBuffer {
+  buffer: Buffer,
+  /// How many instances fit in the buffer.
+  capacity: usize,
+  /// How many instances it holds.
+  length: usize,
+}
+
+/// The number of instances to make room for when `needed` no longer fit,
+/// leaving some to spare so that a growing count doesn't reallocate every
+/// frame.
+fn buffer_capacity(needed: usize) -> usize {
+  needed.max(1).next_power_of_two()
+}
+
+fn prepare_instance_buffers(
+  mut commands: Commands,
+  mut query: Query<(Entity, &ParticleInstances, Option<&mut InstanceBuffer>)>,
+  render_device: Res<RenderDevice>,
+  render_queue: Res<RenderQueue>,
+) {
+  for (entity, instances, instance_buffer) in &mut query {
+    let contents: &[u8] = bytemuck::cast_slice(instances.0.as_slice());
+    let length = instances.0.len();
+    match instance_buffer {
+      Some(mut instance_buffer) if instance_buffer.capacity >= length => {
+        render_queue.write_buffer(&instance_buffer.buffer, 0, contents);
+        instance_buffer.length = length;
+      }
+      _ => {
+        let capacity = buffer_capacity(length);
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+          label: Some("particle instance buffer"),
+          size: (capacity * size_of::<ParticleInstance>()) as u64,
+          usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+          mapped_at_creation: false,
+        });
+        render_queue.write_buffer(&buffer, 0, contents);
+        commands.entity(entity).insert(InstanceBuffer {
+          buffer,
+          capacity,
+          length,
+        });
+      }
+    }
+  }
+}
+
+#[derive(Resource)]
+struct ParticlePipeline {
+  mesh_pipeline: MeshPipeline,
+}
+
+impl FromWorld for ParticlePipeline {
+  fn from_world(world: &mut World) -> Self {
+    ParticlePipeline {
+      mesh_pipeline: world.resource::<MeshPipeline>().clone(),
+    }
+  }
+}
+
+impl SpecializedMeshPipeline for ParticlePipeline {
+  type Key = MeshPipelineKey;
+
+  fn specialize(
+    &self,
+    key: Self::Key,
+    layout: &MeshVertexBufferLayoutRef,
+  ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+    let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
+    descriptor.vertex.shader = PARTICLE_SHADER_HANDLE;
+    // Locations 0 to 2 are the quad's position, normal and UV.
+    descriptor.vertex.buffers.push(VertexBufferLayout {
+      array_stride: size_of::<ParticleInstance>() as u64,
+      step_mode: VertexStepMode::Instance,
+      attributes: vec![
+        VertexAttribute {
+          format: VertexFormat::Float32x4,
+          offset: 0,
+          shader_location: 3,
+        },
+        VertexAttribute {
+          format: VertexFormat::Float32x4,
+          offset: VertexFormat::Float32x4.size(),
+          shader_location: 4,
+        },
+        VertexAttribute {
+          format: VertexFormat::Float32x2,
+          offset: 2 * VertexFormat::Float32x4.size(),
+          shader_location: 5,
+        },
+      ],
+    });
+    descriptor.fragment.as_mut().unwrap().shader = PARTICLE_SHADER_HANDLE;
+    Ok(descriptor)
+  }
+}
+
+type DrawParticles = (
+  SetItemPipeline,
+  SetMeshViewBindGroup<0>,
+  SetMeshBindGroup<1>,
+  DrawMeshInstanced,
+);
+
+struct DrawMeshInstanced;
+
+impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
+  type Param = (
+    SRes<RenderAssets<RenderMesh>>,
+    SRes<RenderMeshInstances>,
+    SRes<MeshAllocator>,
+  );
+  type ViewQuery = ();
+  type ItemQuery = Read<InstanceBuffer>;
+
+  #[inline]
+  fn render<'w>(
+    item: &P,
+    _view: (),
+    instance_buffer: Option<&'w InstanceBuffer>,
+    (meshes, render_mesh_instances, mesh_allocator): SystemParamItem<'w, '_, Self::Param>,
+    pass: &mut TrackedRenderPass<'w>,
+  ) -> RenderCommandResult {
+    // Splitting the borrow out of the resource ref.
+    let mesh_allocator = mesh_allocator.into_inner();
+
+    let Some(mesh_instance) = render_mesh_instances.render_mesh_queue_data(item.main_entity())
+    else {
+      return RenderCommandResult::Skip;
+    };
+    let Some(gpu_mesh) = meshes.into_inner().get(mesh_instance.mesh_asset_id) else {
+      return RenderCommandResult::Skip;
+    };
+    let Some(instance_buffer) = instance_buffer else {
+      return RenderCommandResult::Skip;
+    };
+    let Some(vertex_buffer_slice) = mesh_allocator.mesh_vertex_slice(&mesh_instance.mesh_asset_id)
+    else {
+      return RenderCommandResult::Skip;
+    };
+
+    pass.set_vertex_buffer(0, vertex_buffer_slice.buffer.slice(..));
+    pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
+
+    match &gpu_mesh.buffer_info {
+      RenderMeshBufferInfo::Indexed {
+        index_format,
+        count,
+      } => {
+        let Some(index_buffer_slice) =
+          mesh_allocator.mesh_index_slice(&mesh_instance.mesh_asset_id)
+        else {
+          return RenderCommandResult::Skip;
+        };
+        pass.set_index_buffer(index_buffer_slice.buffer.slice(..), 0, *index_format);
+        pass.draw_indexed(
+          index_buffer_slice.range.start..(index_buffer_slice.range.start + count),
+          vertex_buffer_slice.range.start as i32,
+          0..instance_buffer.length as u32,
+        );
+      }
+      RenderMeshBufferInfo::NonIndexed => {
+        pass.draw(vertex_buffer_slice.range, 0..instance_buffer.length as u32);
+      }
+    }
+    RenderCommandResult::Success
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn instances_are_offset_and_scaled() {
+    let transform = Transform::from_xyz(1.0, 2.0, 0.0)
+      .with_rotation(Quat::from_rotation_z(0.5))
+      .with_scale(Vec3::new(2.0, 1.0, 1.0));
+    let color = [0.1, 0.2, 0.3, 1.0];
+    let instance = ParticleInstance::new(&transform, Vec2::new(10.0, -10.0), 3.0, color, SELECTED);
+    assert_eq!(instance.position_size, [11.0, -8.0, 6.0, 3.0]);
+    assert_eq!(instance.color, color);
+    assert!((instance.rotation_flags[0] - 0.5).abs() < 1e-6);
+    assert_eq!(instance.rotation_flags[1], SELECTED);
+  }
+
+  #[test]
+  fn instance_layout_matches_the_vertex_attributes() {
+    assert_eq!(
+      size_of::<ParticleInstance>() as u64,
+      2 * VertexFormat::Float32x4.size() + VertexFormat::Float32x2.size()
+    );
+  }
+
+  #[test]
+  fn buffer_capacity_leaves_room_to_grow() {
+    assert_eq!(buffer_capacity(0), 1);
+    assert_eq!(buffer_capacity(1), 1);
+    assert_eq!(buffer_capacity(1000), 1024);
+    assert_eq!(buffer_capacity(1024), 1024);
+  }
+}
@@ -0,0 +1,70 @@
+#import bevy_pbr::mesh_view_bindings::view
+
+// Width of the ring drawn around selected and highlighted particles.
+const MARKER_WIDTH: f32 = 3.0;
+const SELECTED: u32 = 1u;
+const HIGHLIGHTED: u32 = 2u;
+const SELECTED_COLOR: vec4<f32> = vec4<f32>(1.0, 1.0, 1.0, 0.5);
+const HIGHLIGHTED_COLOR: vec4<f32> = vec4<f32>(1.0, 0.0, 0.214, 0.5);
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+
+    // Centre, then the radii along and across the direction of motion.
+    @location(3) i_position_size: vec4<f32>,
+    @location(4) i_color: vec4<f32>,
+    // Rotation in radians, then the marker flags.
+    @location(5) i_rotation_flags: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    // Offset from the particle's centre, before rotation.
+    @location(1) local: vec2<f32>,
+    @location(2) size: vec2<f32>,
+    @location(3) @interpolate(flat) flags: u32,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    let size = vertex.i_position_size.zw;
+    let flags = u32(vertex.i_rotation_flags.y);
+    var extent = size;
+    if flags != 0u {
+        extent = vec2<f32>(max(size.x, size.y) + MARKER_WIDTH);
+    }
+    // The quad spans -1 to 1 on both axes.
+    let local = vertex.position.xy * extent;
+    let c = cos(vertex.i_rotation_flags.x);
+    let s = sin(vertex.i_rotation_flags.x);
+    let rotated = vec2<f32>(local.x * c - local.y * s, local.x * s + local.y * c);
+
+    var out: VertexOutput;
+    out.clip_position = view.clip_from_world
+        * vec4<f32>(vertex.i_position_size.xy + rotated, 0.0, 1.0);
+    out.color = vertex.i_color;
+    out.local = local;
+    out.size = size;
+    out.flags = flags;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let scaled = in.local / in.size;
+    if dot(scaled, scaled) <= 1.0 {
+        return in.color;
+    }
+    if length(in.local) <= max(in.size.x, in.size.y) + MARKER_WIDTH {
+        if (in.flags & SELECTED) != 0u {
+            return SELECTED_COLOR;
+        }
+        if (in.flags & HIGHLIGHTED) != 0u {
+            return HIGHLIGHTED_COLOR;
+        }
+    }
+    discard;
+}
@@ -0,0 +1,601 @@
+use bevy::color::palettes::css::WHITE;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::args::ProgramArgs;
+use crate::replay;
+
+/// Everything the viewer does in response to a key or mouse button.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Action {
+  Pause,
+  Step,
+  Fullscreen,
+  Quit,
+  Help,
+  Select,
+  Pan,
+  PanUp,
+  PanDown,
+  PanLeft,
+  PanRight,
+  ZoomIn,
+  ZoomOut,
+  ResetView,
+  FitDish,
+  Screenshot,
+  TogglePlots,
+  CycleColorMode,
+  CyclePalette,
+  NewPaletteSeed,
+  ToggleTrails,
+  ToggleDensity,
+  ToggleIndexOverlay,
+  CycleForceVectors,
+  ToggleTiling,
+  Recenter,
+  ReplayStepForward,
+  ReplayStepBack,
+  ReplaySkipForward,
+  ReplaySkipBack,
+  ReplayStart,
+  ReplayEnd,
+  ReplayFaster,
+  ReplaySlower,
+}
+
+impl Action {
+  pub fn description(self) -> &'static str {
+    match self {
+      Action::Pause => "pause or resume",
+      Action::Step => "advance one tick while paused",
+      Action::Fullscreen => "toggle fullscreen",
+      Action::Quit => "quit",
+      Action::Help => "show or hide this help",
+      Action::Select => "select the particle under the cursor",
+      Action::Pan => "pan the view while held",
+      Action::PanUp => "move the view up",
+      Action::PanDown => "move the view down",
+      Action::PanLeft => "move the view left",
+      Action::PanRight => "move the view right",
+      Action::ZoomIn => "zoom in (the mouse wheel zooms at the cursor)",
+      Action::ZoomOut => "zoom out (the mouse wheel zooms at the cursor)",
+      Action::ResetView => "go back to the starting view",
+      Action::FitDish => "fit the whole dish in view",
+      Action::Screenshot => "save a screenshot",
+      Action::TogglePlots => "show or hide the plots",
+      Action::CycleColorMode => "next color mode",
+      Action::CyclePalette => "next palette",
+      Action::NewPaletteSeed => "new palette seed",
+      Action::ToggleTrails => "show or hide trails",
+      Action::ToggleDensity => "show or hide the density heatmap",
+      Action::ToggleIndexOverlay => "show or hide the spatial index",
+      Action::CycleForceVectors => "cycle force vectors",
+      Action::ToggleTiling => "toggle the tiled view",
+      Action::Recenter => "move the point under the cursor to the middle",
+      Action::ReplayStepForward => "replay: next frame",
+      Action::ReplayStepBack => "replay: previous frame",
+      Action::ReplaySkipForward => "replay: skip ahead",
+      Action::ReplaySkipBack => "replay: skip back",
+      Action::ReplayStart => "replay: go to the start",
+      Action::ReplayEnd => "replay: go to the end",
+      Action::ReplayFaster => "replay: double the speed",
+      Action::ReplaySlower => "replay: halve the speed",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Input {
+  Key(KeyCode),
+  Mouse(MouseButton),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Modifier {
+  Shift,
+  Ctrl,
+  Alt,
+}
+
+impl Modifier {
+  const ALL: [Modifier; 3] = [Modifier::Shift, Modifier::Ctrl, Modifier::Alt];
+
+  fn keys(self) -> [KeyCode; 2] {
+    match self {
+      Modifier::Shift => [KeyCode::ShiftLeft, KeyCode::ShiftRight],
+      Modifier::Ctrl => [KeyCode::ControlLeft, KeyCode::ControlRight],
+      Modifier::Alt => [KeyCode::AltLeft, KeyCode::AltRight],
+    }
+  }
+}
+
+/// An input, along with the modifiers that have to be held with it. Other
+/// modifiers must not be held, so Shift+L and L can do different things.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Binding {
+  pub input: Input,
+  #[serde(default)]
+  pub modifiers: Vec<Modifier>,
+}
+
+impl Binding {
+  fn key(key: KeyCode) -> Binding {
+    Binding {
+      input: Input::Key(key),
+      modifiers: vec![],
+    }
+  }
+
+  fn with(mut self, modifier: Modifier) -> Binding {
+    self.modifiers.push(modifier);
+    self
+  }
+
+  /// Sorts the modifiers and drops repeats, so that bindings held the same way
+  /// compare equal.
+  fn normalise(&mut self) {
+    self.modifiers.sort();
+    self.modifiers.dedup();
+  }
+
+  fn modifiers_held(&self, keys: &ButtonInput<KeyCode>) -> bool {
+    Modifier::ALL
+      .iter()
+      .all(|modifier| self.modifiers.contains(modifier) == keys.any_pressed(modifier.keys()))
+  }
+
+  fn describe(&self) -> String {
+    let mut parts: Vec<String> = self
+      .modifiers
+      .iter()
+      .map(|modifier| format!("{:?}", modifier))
+      .collect();
+    parts.push(match self.input {
+      Input::Key(key) => {
+        let name = format!("{:?}", key);
+        match name.strip_prefix("Key").or(name.strip_prefix("Digit")) {
+          Some(short) => short.to_string(),
+          None => name,
+        }
+      }
+      Input::Mouse(button) => format!("{:?} click", button),
+    });
+    parts.join("+")
+  }
+}
+
+#[derive(Debug)]
+pub enum KeymapError {
+  Io {
+    path: PathBuf,
+    source: io::Error,
+  },
+  Parse {
+    path: PathBuf,
+    source: ron::error::SpannedError,
+  },
+  Conflict {
+    path: PathBuf,
+    binding: String,
+    actions: (Action, Action),
+  },
+}
+
+impl Display for KeymapError {
+  fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+    match self {
+      KeymapError::Io { path, source } => write!(f, "cannot access {:?}: {}", path, source),
+      KeymapError::Parse { path, source } => write!(
+        f,
+        "{}:{}:{}: {}",
+        path.display(),
+        source.position.line,
+        source.position.col,
+        source.code
+      ),
+      KeymapError::Conflict {
+        path,
+        binding,
+        actions: (a, b),
+      } => write!(
+        f,
+        "{}: {} is bound to both {:?} and {:?}",
+        path.display(),
+        binding,
+        a,
+        b
+      ),
+    }
+  }
+}
+
+impl Error for KeymapError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      KeymapError::Io { source, .. } => Some(source),
+      KeymapError::Parse { source, .. } => Some(source),
+      KeymapError::Conflict { .. } => None,
+    }
+  }
+}
+
+/// Bindings for every action. A keymap file only needs the actions it
+/// changes; the rest keep their default bindings.
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
+pub struct Keymap {
+  pub bindings: BTreeMap<Action, Vec<Binding>>,
+}
+
+impl Default for Keymap {
+  fn default() -> Keymap {
+    use Action::*;
+    use Modifier::*;
+    let mouse = |button| Binding {
+      input: Input::Mouse(button),
+      modifiers: vec![],
+    };
+    let bindings = [
+      (Pause, vec![Binding::key(KeyCode::Space)]),
+      (Step, vec![Binding::key(KeyCode::Period)]),
+      (Fullscreen, vec![Binding::key(KeyCode::KeyF)]),
+      (Quit, vec![Binding::key(KeyCode::Escape)]),
+      (
+        Help,
+        vec![
+          Binding::key(KeyCode::F1),
+          Binding::key(KeyCode::Slash).with(Shift),
+        ],
+      ),
+      (Select, vec![mouse(MouseButton::Left)]),
+      (Pan, vec![mouse(MouseButton::Left).with(Ctrl)]),
+      (
+        PanUp,
+        vec![Binding::key(KeyCode::KeyW), Binding::key(KeyCode::ArrowUp)],
+      ),
+      (
+        PanDown,
+        vec![
+          Binding::key(KeyCode::KeyS),
+          Binding::key(KeyCode::ArrowDown),
+        ],
+      ),
+      (
+        PanLeft,
+        vec![
+          Binding::key(KeyCode::KeyA),
+          Binding::key(KeyCode::ArrowLeft),
+        ],
+      ),
+      (
+        PanRight,
+        vec![
+          Binding::key(KeyCode::KeyD),
+          Binding::key(KeyCode::ArrowRight),
+        ],
+      ),
+      (ZoomIn, vec![Binding::key(KeyCode::Equal)]),
+      (ZoomOut, vec![Binding::key(KeyCode::Minus)]),
+      (ResetView, vec![Binding::key(KeyCode::KeyR)]),
+      (FitDish, vec![Binding::key(KeyCode::KeyZ)]),
+      (Screenshot, vec![Binding::key(KeyCode::F12)]),
+      (TogglePlots, vec![Binding::key(KeyCode::KeyP)]),
+      (CycleColorMode, vec![Binding::key(KeyCode::KeyC)]),
+      (CyclePalette, vec![Binding::key(KeyCode::KeyL)]),
+      (
+        NewPaletteSeed,
+        vec![Binding::key(KeyCode::KeyL).with(Shift)],
+      ),
+      (ToggleTrails, vec![Binding::key(KeyCode::KeyT)]),
+      (ToggleDensity, vec![Binding::key(KeyCode::KeyH)]),
+      (ToggleIndexOverlay, vec![Binding::key(KeyCode::KeyB)]),
+      (CycleForceVectors, vec![Binding::key(KeyCode::KeyV)]),
+      (ToggleTiling, vec![Binding::key(KeyCode::KeyG)]),
+      (Recenter, vec![Binding::key(KeyCode::KeyX)]),
+      (ReplayStepForward, vec![Binding::key(KeyCode::ArrowRight)]),
+      (ReplayStepBack, vec![Binding::key(KeyCode::ArrowLeft)]),
+      (
+        ReplaySkipForward,
+        vec![Binding::key(KeyCode::ArrowRight).with(Shift)],
+      ),
+      (
+        ReplaySkipBack,
+        vec![Binding::key(KeyCode::ArrowLeft).with(Shift)],
+      ),
+      (ReplayStart, vec![Binding::key(KeyCode::Home)]),
+      (ReplayEnd, vec![Binding::key(KeyCode::End)]),
+      (ReplayFaster, vec![Binding::key(KeyCode::BracketRight)]),
+      (ReplaySlower, vec![Binding::key(KeyCode::BracketLeft)]),
+    ];
+    Keymap {
+      bindings: bindings.into_iter().collect(),
+    }
+  }
+}
+
+impl Keymap {
+  /// Reads a keymap file, on top of the default bindings.
+  pub fn load(path: &Path) -> Result<Keymap, KeymapError> {
+    let contents = fs::read_to_string(path).map_err(|source| KeymapError::Io {
+      path: path.to_path_buf(),
+      source,
+    })?;
+    let file: Keymap = ron::de::from_str(&contents).map_err(|source| KeymapError::Parse {
+      path: path.to_path_buf(),
+      source,
+    })?;
+    let mut keymap = Keymap::default();
+    keymap.bindings.extend(file.bindings);
+    for binding in keymap.bindings.values_mut().flatten() {
+      binding.normalise();
+    }
+    if let Some((binding, actions)) = keymap.conflict() {
+      return Err(KeymapError::Conflict {
+        path: path.to_path_buf(),
+        binding,
+        actions,
+      });
+    }
+    Ok(keymap)
+  }
+
+  /// A binding shared by two actions, other than a replay action sharing one
+  /// with an action that isn't, which `give_precedence` sorts out.
+  fn conflict(&self) -> Option<(String, (Action, Action))> {
+    let bound: Vec<(Action, &Binding)> = self
+      .bindings
+      .iter()
+      .flat_map(|(&action, bindings)| bindings.iter().map(move |binding| (action, binding)))
+      .collect();
+    bound.iter().enumerate().find_map(|(i, &(a, binding))| {
+      bound[i + 1..]
+        .iter()
+        .find(|&&(b, other)| {
+          other == binding && a != b && replay::ACTIONS.contains(&a) == replay::ACTIONS.contains(&b)
+        })
+        .map(|&(b, _)| (binding.describe(), (a, b)))
+    })
+  }
+
+  /// Takes the bindings of the given actions away from every other action, so
+  /// that they alone respond to them.
+  pub fn give_precedence(&mut self, actions: &[Action]) {
+    let claimed: Vec<Binding> = actions
+      .iter()
+      .flat_map(|&action| self.bindings(action).to_vec())
+      .collect();
+    for (action, bindings) in self.bindings.iter_mut() {
+      if !actions.contains(action) {
+        bindings.retain(|binding| !claimed.contains(binding));
+      }
+    }
+  }
+
+  pub fn from_args(args: &ProgramArgs) -> Result<Keymap, KeymapError> {
+    match &args.keymap {
+      Some(path) => Keymap::load(path),
+      None => Ok(Keymap::default()),
+    }
+  }
+
+  fn bindings(&self, action: Action) -> &[Binding] {
+    self.bindings.get(&action).map_or(&[], Vec::as_slice)
+  }
+
+  pub fn just_pressed(
+    &self,
+    action: Action,
+    keys: &ButtonInput<KeyCode>,
+    mouse: &ButtonInput<MouseButton>,
+  ) -> bool {
+    self.bindings(action).iter().any(|binding| {
+      binding.modifiers_held(keys)
+        && match binding.input {
+          Input::Key(key) => keys.just_pressed(key),
+          Input::Mouse(button) => mouse.just_pressed(button),
+        }
+    })
+  }
+
+  pub fn pressed(
+    &self,
+    action: Action,
+    keys: &ButtonInput<KeyCode>,
+    mouse: &ButtonInput<MouseButton>,
+  ) -> bool {
+    self.bindings(action).iter().any(|binding| {
+      binding.modifiers_held(keys)
+        && match binding.input {
+          Input::Key(key) => keys.pressed(key),
+          Input::Mouse(button) => mouse.pressed(button),
+        }
+    })
+  }
+
+  /// Modifiers are ignored here, so letting go of them first still ends the
+  /// action.
+  pub fn just_released(
+    &self,
+    action: Action,
+    keys: &ButtonInput<KeyCode>,
+    mouse: &ButtonInput<MouseButton>,
+  ) -> bool {
+    self
+      .bindings(action)
+      .iter()
+      .any(|binding| match binding.input {
+        Input::Key(key) => keys.just_released(key),
+        Input::Mouse(button) => mouse.just_released(button),
+      })
+  }
+
+  /// The bindings of an action, as shown to the user.
+  pub fn describe(&self, action: Action) -> String {
+    let bindings = self.bindings(action);
+    if bindings.is_empty() {
+      return "unbound".to_string();
+    }
+    bindings
+      .iter()
+      .map(Binding::describe)
+      .collect::<Vec<_>>()
+      .join(", ")
+  }
+}
+
+/// The keymap together with the input state it's checked against.
+#[derive(SystemParam)]
+pub struct Actions<'w> {
+  keymap: Res<'w, Keymap>,
+  keys: Res<'w, ButtonInput<KeyCode>>,
+  mouse: Res<'w, ButtonInput<MouseButton>>,
+}
+
+impl Actions<'_> {
+  pub fn just_pressed(&self, action: Action) -> bool {
+    self.keymap.just_pressed(action, &self.keys, &self.mouse)
+  }
+
+  pub fn pressed(&self, action: Action) -> bool {
+    self.keymap.pressed(action, &self.keys, &self.mouse)
+  }
+
+  pub fn just_released(&self, action: Action) -> bool {
+    self.keymap.just_released(action, &self.keys, &self.mouse)
+  }
+
+  pub fn describe(&self, action: Action) -> String {
+    self.keymap.describe(action)
+  }
+}
+
+#[derive(Component)]
+pub struct HelpOverlay;
+
+pub fn init_help(mut commands: Commands, asset_server: Res<AssetServer>, keymap: Res<Keymap>) {
+  let text = keymap
+    .bindings
+    .keys()
+    .map(|&action| format!("{:<20} {}", keymap.describe(action), action.description()))
+    .collect::<Vec<_>>()
+    .join("\n");
+  commands.spawn((
+    HelpOverlay,
+    Text::new(text),
+    TextFont {
+      font: asset_server.load("FiraMono-Regular.ttf"),
+      font_size: 14.0,
+      ..Default::default()
+    },
+    TextColor(WHITE.into()),
+    BackgroundColor(Color::BLACK.with_alpha(0.8)),
+    Visibility::Hidden,
+    Node {
+      position_type: PositionType::Absolute,
+      top: Val::Px(40.0),
+      left: Val::Px(40.0),
+      padding: UiRect::all(Val::Px(10.0)),
+      ..Default::default()
+    },
+  ));
+}
+
+pub fn toggle_help(actions: Actions, mut help: Query<&mut Visibility, With<HelpOverlay>>) {
+  if !actions.just_pressed(Action::Help) {
+    return;
+  }
+  for mut visibility in help.iter_mut() {
+    *visibility = match *visibility {
+      Visibility::Hidden => Visibility::Inherited,
+      _ => Visibility::Hidden,
+    };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn load_str(name: &str, contents: &str) -> Result<Keymap, KeymapError> {
+    let path = std::env::temp_dir().join(format!("partikl-{}-{}.ron", name, std::process::id()));
+    fs::write(&path, contents).unwrap();
+    let keymap = Keymap::load(&path);
+    let _ = fs::remove_file(&path);
+    keymap
+  }
+
+  #[test]
+  fn defaults_have_no_conflicts() {
+    assert_eq!(Keymap::default().conflict(), None);
+  }
+
+  #[test]
+  fn file_overrides_defaults() {
+    let keymap = load_str(
+      "override",
+      "(bindings: { Pause: [(input: Key(KeyK))], Pan: [(input: Mouse(Right), modifiers: [Ctrl])] })",
+    )
+    .unwrap();
+    assert_eq!(keymap.describe(Action::Pause), "K");
+    assert_eq!(keymap.describe(Action::Pan), "Ctrl+Right click");
+    assert_eq!(keymap.describe(Action::Quit), "Escape");
+  }
+
+  #[test]
+  fn shared_bindings_are_rejected() {
+    // P also toggles the plots.
+    match load_str("conflict", "(bindings: { Pause: [(input: Key(KeyP))] })") {
+      Err(KeymapError::Conflict {
+        binding, actions, ..
+      }) => {
+        assert_eq!(binding, "P");
+        assert_eq!(actions, (Action::Pause, Action::TogglePlots));
+      }
+      other => panic!("expected a conflict, got {:?}", other.map(|_| ())),
+    }
+    // Replay actions may share keys with the rest, as they take precedence.
+    assert!(load_str(
+      "replay",
+      "(bindings: { ReplayStart: [(input: Key(KeyW))] })"
+    )
+    .is_ok());
+  }
+
+  #[test]
+  fn modifier_order_does_not_matter() {
+    match load_str(
+      "modifiers",
+      "(bindings: { Pause: [(input: Key(KeyK), modifiers: [Ctrl, Shift])], \
+       Quit: [(input: Key(KeyK), modifiers: [Shift, Ctrl, Shift])] })",
+    ) {
+      Err(KeymapError::Conflict { binding, .. }) => assert_eq!(binding, "Shift+Ctrl+K"),
+      other => panic!("expected a conflict, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn parse_errors_name_the_file_and_position() {
+    let err = load_str("parse", "(bindings: {\n  Pause: [(input: Key(Nope))] })")
+      .map(|_| ())
+      .unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains("partikl-parse-"), "{}", message);
+    assert!(message.contains(".ron:2:"), "{}", message);
+  }
+
+  #[test]
+  fn give_precedence_unbinds_others() {
+    let mut keymap = Keymap::default();
+    keymap.give_precedence(&replay::ACTIONS);
+    assert_eq!(keymap.describe(Action::PanLeft), "A");
+    assert_eq!(keymap.describe(Action::ReplayStepBack), "ArrowLeft");
+  }
+}
@@ -2,113 +2,534 @@ use chrono::offset::Utc;
 
 use rand::Rng;
 
-use ron::de::from_reader;
+use ron::de::from_str;
 use ron::ser::{to_writer_pretty, PrettyConfig};
 
 use std::error::Error;
-use std::fmt::{Debug, Display, Formatter};
-use std::fs::File;
-use std::path::PathBuf;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 
 use crate::args;
 use crate::core;
+use crate::generate;
+use crate::palette;
 
-pub fn get_particle_spec(program_args: &args::ProgramArgs) -> core::ParticleSpec {
+pub fn get_particle_spec(
+  program_args: &args::ProgramArgs,
+) -> Result<core::ParticleSpec, SpecError> {
+  let particle_spec = load_or_generate_spec(program_args)?;
+  if program_args.interaction_spec.is_none() && !program_args.no_dump_interaction_spec {
+    let path = PathBuf::from(format!("spec-{}.ron", Utc::now().format("%F-%H-%M-%S")));
+    write_spec_file(&particle_spec, &path)?;
+  }
+  Ok(particle_spec)
+}
+
+/// Like `get_particle_spec`, but never writes a generated spec to disk.
+pub fn load_or_generate_spec(
+  program_args: &args::ProgramArgs,
+) -> Result<core::ParticleSpec, SpecError> {
   if let Some(path) = &program_args.interaction_spec {
-    let file = File::open(path).expect(&format!("failed to open file at {:?}", path));
-    let interaction_list = from_reader(file).expect(&format!("failed to parse file at {:?}", path));
-    validate_interaction_list(interaction_list).expect("malformed spec")
+    let mut particle_spec = load_spec_file(path)?;
+    palette::assign_colors(program_args, &mut particle_spec);
+    Ok(particle_spec)
   } else {
-    use rand::SeedableRng;
-
-    let mut rng = if let Some(seed) = program_args.interaction_seed {
-      let b = seed.to_le_bytes();
-      let more_bytes = [
-        b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[0], b[1], b[2], b[3], b[4], b[5], b[6],
-        b[7], b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[0], b[1], b[2], b[3], b[4], b[5],
-        b[6], b[7],
-      ];
-      rand::rngs::SmallRng::from_seed(more_bytes)
-    } else {
-      rand::rngs::SmallRng::from_entropy()
-    };
+    let seed = program_args
+      .interaction_seed
+      .unwrap_or_else(|| rand::thread_rng().gen());
+    let mut rng = generate::seeded_rng(seed);
     let type_count = program_args.num_types;
-    let particle_spec = generate_particle_spec(&mut rng, type_count);
-    if !program_args.no_dump_interaction_spec {
-      let path = PathBuf::from(format!("spec-{}.ron", Utc::now().format("%F-%H-%M-%S")));
-      let file = File::create(&path).unwrap();
-      to_writer_pretty(file, &particle_spec.interactions, PrettyConfig::default())
-        .expect(&format!("failed to write to {:?}", path));
+    if type_count == 0 {
+      return Err(SpecError::Empty);
     }
-    particle_spec
+    let config = generate::GeneratorConfig::from_args(program_args);
+    config.validate()?;
+    let mut particle_spec = generate::generate_particle_spec(&mut rng, type_count, &config);
+    particle_spec.info.seed = Some(seed);
+    particle_spec.info.created = Some(Utc::now());
+    palette::assign_colors(program_args, &mut particle_spec);
+    Ok(particle_spec)
   }
 }
 
-fn validate_interaction_list(
-  interactions: Vec<core::Interaction>,
-) -> Result<core::ParticleSpec, impl Error> {
-  let total_interactions = interactions.len();
-  interactions
-    .iter()
-    .map(|interaction| validate_single_interaction(interaction, total_interactions))
-    .fold(Ok(()), |acc, r| acc.and(r))
-    .and_then(|_| {
-      Ok(core::ParticleSpec {
+/// Reads and validates a spec file, accepting both the versioned format and
+/// the legacy bare list of interactions.
+pub fn load_spec_file(path: &Path) -> Result<core::ParticleSpec, SpecError> {
+  let contents = fs::read_to_string(path).map_err(|source| SpecError::Io {
+    path: path.to_path_buf(),
+    source,
+  })?;
+  let spec_file = match from_str::<core::SpecFile>(&contents) {
+    Ok(spec_file) => spec_file,
+    Err(err) => match from_str::<Vec<core::Interaction>>(&contents) {
+      Ok(interactions) => core::SpecFile {
+        version: 0,
+        name: None,
+        description: None,
+        seed: None,
+        created: None,
+        kernel: core::KernelParams::default(),
+        type_names: vec![],
+        colors: vec![],
         interactions,
-        ..Default::default()
-      })
-    })
+      },
+      Err(_) => {
+        return Err(SpecError::Parse {
+          path: path.to_path_buf(),
+          source: err,
+        })
+      }
+    },
+  };
+  validate_spec_file(spec_file)
 }
 
-fn validate_single_interaction(
-  interaction: &core::Interaction,
+pub fn write_spec_file(particle_spec: &core::ParticleSpec, path: &Path) -> Result<(), SpecError> {
+  let file = File::create(path).map_err(|source| SpecError::Io {
+    path: path.to_path_buf(),
+    source,
+  })?;
+  to_writer_pretty(file, &particle_spec.to_file(), PrettyConfig::default()).map_err(|source| {
+    SpecError::Write {
+      path: path.to_path_buf(),
+      source,
+    }
+  })
+}
+
+fn validate_spec_file(spec_file: core::SpecFile) -> Result<core::ParticleSpec, SpecError> {
+  if spec_file.version > core::SPEC_FORMAT_VERSION {
+    return Err(SpecError::UnsupportedVersion {
+      found: spec_file.version,
+      supported: core::SPEC_FORMAT_VERSION,
+    });
+  }
+  let interactions = &spec_file.interactions;
+  if interactions.is_empty() {
+    return Err(SpecError::Empty);
+  }
+  let total_interactions = interactions.len();
+  for (row, interaction) in interactions.iter().enumerate() {
+    validate_single_interaction(interaction, row, total_interactions)?;
+  }
+  validate_kernel_params(&spec_file.kernel)?;
+  validate_per_type_list("type_names", spec_file.type_names.len(), total_interactions)?;
+  validate_per_type_list("colors", spec_file.colors.len(), total_interactions)?;
+  Ok(core::ParticleSpec::from_file(spec_file))
+}
+
+fn validate_per_type_list(
+  field: &'static str,
+  len: usize,
   total_interactions: usize,
-) -> Result<(), impl Error> {
-  let total_coeffs = interaction.force_coeffs.len();
-  if total_coeffs == total_interactions {
+) -> Result<(), SpecError> {
+  if len == 0 || len == total_interactions {
     Ok(())
   } else {
-    Err(MalformedInteractionError {
-      total_interactions,
-      total_coeffs,
+    Err(SpecError::PerTypeLength {
+      field,
+      expected: total_interactions,
+      got: len,
     })
   }
 }
 
-fn generate_particle_spec(rng: &mut impl Rng, type_count: usize) -> core::ParticleSpec {
-  let interactions = (0..type_count)
-    .map(|_| generate_single_interaction(rng, type_count))
-    .fold(vec![], |mut acc, i| {
-      acc.push(i);
-      acc
+fn validate_single_interaction(
+  interaction: &core::Interaction,
+  row: usize,
+  total_interactions: usize,
+) -> Result<(), SpecError> {
+  let total_coeffs = interaction.force_coeffs.len();
+  if total_coeffs != total_interactions {
+    return Err(SpecError::RowLength {
+      row,
+      expected: total_interactions,
+      got: total_coeffs,
     });
-  core::ParticleSpec {
-    interactions,
-    ..Default::default()
+  }
+  match interaction
+    .force_coeffs
+    .iter()
+    .position(|coeff| !coeff.is_finite())
+  {
+    Some(column) => Err(SpecError::NonFiniteCoefficient {
+      row,
+      column,
+      value: interaction.force_coeffs[column],
+    }),
+    None => Ok(()),
   }
 }
 
-fn generate_single_interaction(rng: &mut impl Rng, type_count: usize) -> core::Interaction {
-  core::Interaction {
-    force_coeffs: (0..type_count)
-      .map(|_| 1000.0 * rng.gen::<f32>() - 500.0)
-      .collect(),
+pub fn validate_kernel_params(kernel: &core::KernelParams) -> Result<(), SpecError> {
+  let fields = [
+    ("repulsion_radius", kernel.repulsion_radius),
+    ("repulsion_strength", kernel.repulsion_strength),
+    ("peak_distance", kernel.peak_distance),
+    ("peak_width", kernel.peak_width),
+    ("interaction_radius", kernel.interaction_radius),
+  ];
+  for (name, value) in fields {
+    if !value.is_finite() || value <= 0.0 {
+      return Err(SpecError::KernelParam {
+        name,
+        value,
+        reason: "must be a positive finite number",
+      });
+    }
+  }
+  if !kernel.friction.is_finite() || kernel.friction < 0.0 {
+    return Err(SpecError::KernelParam {
+      name: "friction",
+      value: kernel.friction,
+      reason: "must be a non-negative finite number",
+    });
   }
+  if kernel.repulsion_radius >= kernel.interaction_radius {
+    return Err(SpecError::KernelParam {
+      name: "repulsion_radius",
+      value: kernel.repulsion_radius,
+      reason: "must be smaller than interaction_radius",
+    });
+  }
+  if kernel.peak_distance + kernel.peak_width > kernel.interaction_radius {
+    return Err(SpecError::KernelParam {
+      name: "peak_width",
+      value: kernel.peak_width,
+      reason: "peak_distance + peak_width must not exceed interaction_radius",
+    });
+  }
+  Ok(())
 }
 
 #[derive(Debug)]
-struct MalformedInteractionError {
-  total_interactions: usize,
-  total_coeffs: usize,
+pub enum SpecError {
+  Io {
+    path: PathBuf,
+    source: std::io::Error,
+  },
+  Parse {
+    path: PathBuf,
+    source: ron::error::SpannedError,
+  },
+  Write {
+    path: PathBuf,
+    source: ron::Error,
+  },
+  UnsupportedVersion {
+    found: u32,
+    supported: u32,
+  },
+  Empty,
+  RowLength {
+    row: usize,
+    expected: usize,
+    got: usize,
+  },
+  NonFiniteCoefficient {
+    row: usize,
+    column: usize,
+    value: f32,
+  },
+  KernelParam {
+    name: &'static str,
+    value: f32,
+    reason: &'static str,
+  },
+  PerTypeLength {
+    field: &'static str,
+    expected: usize,
+    got: usize,
+  },
+  GeneratorParam {
+    name: &'static str,
+    reason: &'static str,
+  },
 }
 
-impl Display for MalformedInteractionError {
+impl Display for SpecError {
   fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-    f.write_fmt(format_args!(
-      "expected {} coefficients, got {}",
-      self.total_interactions, self.total_coeffs
-    ))
+    match self {
+      SpecError::Io { path, source } => write!(f, "cannot access {:?}: {}", path, source),
+      SpecError::Parse { path, source } => write!(
+        f,
+        "{}:{}:{}: {}",
+        path.display(),
+        source.position.line,
+        source.position.col,
+        source.code
+      ),
+      SpecError::Write { path, source } => write!(f, "failed to write {:?}: {}", path, source),
+      SpecError::UnsupportedVersion { found, supported } => write!(
+        f,
+        "spec format version {} is newer than the supported version {}",
+        found, supported
+      ),
+      SpecError::Empty => write!(f, "spec contains no particle types"),
+      SpecError::RowLength { row, expected, got } => write!(
+        f,
+        "row {}: expected {} coefficients, got {}",
+        row, expected, got
+      ),
+      SpecError::NonFiniteCoefficient { row, column, value } => write!(
+        f,
+        "row {}, column {}: coefficient must be finite, got {}",
+        row, column, value
+      ),
+      SpecError::KernelParam {
+        name,
+        value,
+        reason,
+      } => write!(f, "kernel parameter {} = {}: {}", name, value, reason),
+      SpecError::PerTypeLength {
+        field,
+        expected,
+        got,
+      } => write!(
+        f,
+        "{}: expected {} entries (one per type), got {}",
+        field, expected, got
+      ),
+      SpecError::GeneratorParam { name, reason } => write!(f, "--{}: {}", name, reason),
+    }
   }
 }
 
-impl Error for MalformedInteractionError {}
+impl Error for SpecError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      SpecError::Io { source, .. } => Some(source),
+      SpecError::Parse { source, .. } => Some(source),
+      SpecError::Write { source, .. } => Some(source),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn spec_file(rows: &[&[f32]]) -> core::SpecFile {
+    core::SpecFile {
+      version: core::SPEC_FORMAT_VERSION,
+      name: None,
+      description: None,
+      seed: None,
+      created: None,
+      kernel: core::KernelParams::default(),
+      type_names: vec![],
+      colors: vec![],
+      interactions: rows
+        .iter()
+        .map(|row| core::Interaction {
+          force_coeffs: row.to_vec(),
+        })
+        .collect(),
+    }
+  }
+
+  fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("partikl-spec-{}-{}.ron", name, std::process::id()))
+  }
+
+  fn load_str(name: &str, contents: &str) -> Result<core::ParticleSpec, SpecError> {
+    let path = temp_path(name);
+    fs::write(&path, contents).unwrap();
+    let spec = load_spec_file(&path);
+    let _ = fs::remove_file(&path);
+    spec
+  }
+
+  fn coeffs(spec: &core::ParticleSpec) -> Vec<Vec<f32>> {
+    spec
+      .interactions
+      .iter()
+      .map(|interaction| interaction.force_coeffs.clone())
+      .collect()
+  }
+
+  fn kernel_error(change: impl FnOnce(&mut core::KernelParams)) -> Option<&'static str> {
+    let mut kernel = core::KernelParams::default();
+    change(&mut kernel);
+    match validate_kernel_params(&kernel) {
+      Err(SpecError::KernelParam { name, .. }) => Some(name),
+      Err(err) => panic!("unexpected error: {}", err),
+      Ok(()) => None,
+    }
+  }
+
+  #[test]
+  fn valid_spec_is_accepted() {
+    let spec = validate_spec_file(spec_file(&[&[1.0, -2.0], &[0.0, 3.5]])).unwrap();
+    assert_eq!(spec.interactions.len(), 2);
+  }
+
+  #[test]
+  fn short_row_is_rejected() {
+    match validate_spec_file(spec_file(&[&[1.0, 2.0], &[3.0]])) {
+      Err(SpecError::RowLength { row, expected, got }) => {
+        assert_eq!((row, expected, got), (1, 2, 1))
+      }
+      other => panic!("expected a row length error, got {:?}", other.err()),
+    }
+  }
+
+  #[test]
+  fn nan_coefficient_is_rejected() {
+    match validate_spec_file(spec_file(&[&[1.0, f32::NAN], &[0.0, 0.0]])) {
+      Err(SpecError::NonFiniteCoefficient { row, column, .. }) => assert_eq!((row, column), (0, 1)),
+      other => panic!("expected a non-finite coefficient, got {:?}", other.err()),
+    }
+  }
+
+  #[test]
+  fn infinite_coefficient_is_rejected() {
+    match validate_spec_file(spec_file(&[&[1.0, 0.0], &[f32::NEG_INFINITY, 0.0]])) {
+      Err(SpecError::NonFiniteCoefficient { row, column, .. }) => assert_eq!((row, column), (1, 0)),
+      other => panic!("expected a non-finite coefficient, got {:?}", other.err()),
+    }
+  }
+
+  #[test]
+  fn empty_spec_is_rejected() {
+    assert!(matches!(
+      validate_spec_file(spec_file(&[])),
+      Err(SpecError::Empty)
+    ));
+  }
+
+  #[test]
+  fn newer_version_is_rejected() {
+    let mut file = spec_file(&[&[0.0]]);
+    file.version = core::SPEC_FORMAT_VERSION + 1;
+    assert!(matches!(
+      validate_spec_file(file),
+      Err(SpecError::UnsupportedVersion { .. })
+    ));
+  }
+
+  #[test]
+  fn default_kernel_is_valid() {
+    assert_eq!(kernel_error(|_| ()), None);
+  }
+
+  #[test]
+  fn non_positive_kernel_param_is_rejected() {
+    assert_eq!(
+      kernel_error(|kernel| kernel.peak_width = 0.0),
+      Some("peak_width")
+    );
+    assert_eq!(
+      kernel_error(|kernel| kernel.repulsion_strength = -1.0),
+      Some("repulsion_strength")
+    );
+  }
+
+  #[test]
+  fn non_finite_kernel_param_is_rejected() {
+    assert_eq!(
+      kernel_error(|kernel| kernel.interaction_radius = f32::INFINITY),
+      Some("interaction_radius")
+    );
+    assert_eq!(
+      kernel_error(|kernel| kernel.friction = f32::NAN),
+      Some("friction")
+    );
+  }
+
+  #[test]
+  fn negative_friction_is_rejected() {
+    assert_eq!(
+      kernel_error(|kernel| kernel.friction = -0.1),
+      Some("friction")
+    );
+  }
+
+  #[test]
+  fn repulsion_beyond_interaction_radius_is_rejected() {
+    assert_eq!(
+      kernel_error(|kernel| kernel.repulsion_radius = kernel.interaction_radius),
+      Some("repulsion_radius")
+    );
+  }
+
+  #[test]
+  fn peak_beyond_interaction_radius_is_rejected() {
+    assert_eq!(
+      kernel_error(|kernel| kernel.peak_distance = kernel.interaction_radius),
+      Some("peak_width")
+    );
+  }
+
+  #[test]
+  fn versioned_spec_round_trips() {
+    let mut file = spec_file(&[&[1.5, -2.0], &[0.0, 300.0]]);
+    file.name = Some("pair".to_string());
+    file.seed = Some(42);
+    file.kernel.friction = 0.5;
+    file.type_names = vec!["red".to_string(), "blue".to_string()];
+    file.colors = vec![[1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+    let spec = validate_spec_file(file).unwrap();
+
+    let path = temp_path("versioned");
+    write_spec_file(&spec, &path).unwrap();
+    let loaded = load_spec_file(&path);
+    let _ = fs::remove_file(&path);
+    let loaded = loaded.unwrap();
+
+    assert_eq!(coeffs(&loaded), coeffs(&spec));
+    assert_eq!(loaded.kernel, spec.kernel);
+    assert_eq!(loaded.info.name.as_deref(), Some("pair"));
+    assert_eq!(loaded.info.seed, Some(42));
+    assert_eq!(loaded.info.type_names, spec.info.type_names);
+    assert_eq!(loaded.info.colors, spec.info.colors);
+  }
+
+  #[test]
+  fn legacy_bare_list_still_loads() {
+    let interactions = spec_file(&[&[1.0, 2.0], &[-3.0, 4.0]]).interactions;
+    let contents = ron::ser::to_string(&interactions).unwrap();
+    let loaded = load_str("legacy", &contents).unwrap();
+    assert_eq!(coeffs(&loaded), vec![vec![1.0, 2.0], vec![-3.0, 4.0]]);
+    assert_eq!(loaded.kernel, core::KernelParams::default());
+    assert_eq!(loaded.info.name, None);
+    assert!(loaded.info.colors.is_empty());
+  }
+
+  #[test]
+  fn legacy_bare_list_is_validated() {
+    assert!(matches!(
+      load_str(
+        "legacy-short",
+        "[(force_coeffs: [1.0, 2.0]), (force_coeffs: [3.0])]"
+      ),
+      Err(SpecError::RowLength { row: 1, .. })
+    ));
+  }
+
+  #[test]
+  fn per_type_lists_must_match_the_type_count() {
+    let mut file = spec_file(&[&[0.0, 0.0], &[0.0, 0.0]]);
+    file.type_names = vec!["only".to_string()];
+    match validate_spec_file(file) {
+      Err(SpecError::PerTypeLength {
+        field,
+        expected,
+        got,
+      }) => assert_eq!((field, expected, got), ("type_names", 2, 1)),
+      other => panic!("expected a length mismatch, got {:?}", other.err()),
+    }
+
+    let mut file = spec_file(&[&[0.0, 0.0], &[0.0, 0.0]]);
+    file.colors = vec![[1.0, 1.0, 1.0]; 3];
+    assert!(matches!(
+      validate_spec_file(file),
+      Err(SpecError::PerTypeLength {
+        field: "colors",
+        ..
+      })
+    ));
+  }
+}
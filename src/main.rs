@@ -2,34 +2,158 @@ use core::{SimState, DELTA_TIME};
 
 use bevy::diagnostic::FrameTimeDiagnosticsPlugin;
 use bevy::prelude::*;
-use bevy::window::{WindowMode, WindowResolution};
+use bevy::time::TimeUpdateStrategy;
 use clap::Parser;
 
 mod args;
+mod capture;
+mod clusters;
+mod coloring;
 mod core;
+mod density;
+mod dishes;
+mod evolve;
+mod export;
+mod fitness;
+mod forces;
+mod generate;
+mod headless;
+mod index_overlay;
+mod instanced;
+mod keymap;
 mod loading;
+mod observables;
+mod offscreen;
+mod palette;
+mod plots;
+mod raster;
+mod record;
+mod reload;
 mod render;
+mod replay;
 mod sim;
+mod sweep;
+mod tiling;
+mod trails;
 mod ui;
 
 fn main() {
-  let program_args = args::ProgramArgs::parse();
-  App::new()
-    .insert_resource(loading::get_particle_spec(&program_args))
+  let mut program_args = args::ProgramArgs::parse();
+  if let Some(command) = &program_args.command {
+    let result = match command {
+      args::Command::Evolve(evolve_args) => evolve::run(&program_args, evolve_args),
+      args::Command::Sweep(sweep_args) => sweep::run(&program_args, sweep_args),
+      args::Command::Export(export_args) => export::run(&program_args, export_args),
+    };
+    if let Err(err) = result {
+      eprintln!("error: {}", err);
+      std::process::exit(1);
+    }
+    return;
+  }
+  let replay = match program_args.replay.as_deref().map(replay::Replay::load) {
+    Some(Ok(replay)) => Some(replay),
+    Some(Err(err)) => {
+      eprintln!("error: cannot load replay: {}", err);
+      std::process::exit(1);
+    }
+    None => None,
+  };
+  if let Some(replay) = &replay {
+    // Spawn exactly the recorded particles, and generate a throwaway spec with
+    // enough types to color them if none was given.
+    program_args.num_particles = replay.particle_count();
+    program_args.num_types = replay.type_count();
+    program_args.no_dump_interaction_spec = true;
+  }
+  program_args.layout_seed.get_or_insert_with(rand::random);
+  let spec_watch = reload::SpecWatch::start(program_args.interaction_spec.as_deref());
+  let particle_spec = match loading::get_particle_spec(&program_args) {
+    Ok(particle_spec) => particle_spec,
+    Err(err) => {
+      eprintln!("error: {}", err);
+      std::process::exit(1);
+    }
+  };
+  if let Some(replay) = &replay {
+    // A spec given with --interaction-spec has to cover every recorded type.
+    if replay.type_count() > particle_spec.interactions.len() {
+      eprintln!(
+        "error: replay has {} particle types, but the spec only has {}",
+        replay.type_count(),
+        particle_spec.interactions.len()
+      );
+      std::process::exit(1);
+    }
+  }
+  let recorder = match record::Recorder::start(&program_args) {
+    Ok(recorder) => recorder,
+    Err(err) => {
+      eprintln!("error: cannot start recording: {}", err);
+      std::process::exit(1);
+    }
+  };
+  let frame_capture = match capture::FrameCapture::start(&program_args) {
+    Ok(frame_capture) => frame_capture,
+    Err(err) => {
+      eprintln!("error: cannot start frame capture: {}", err);
+      std::process::exit(1);
+    }
+  };
+  let dishes = match dishes::Dishes::load(&program_args) {
+    Ok(dishes) => dishes,
+    Err(err) => {
+      eprintln!("error: {}", err);
+      std::process::exit(1);
+    }
+  };
+  let mut keymap = match keymap::Keymap::from_args(&program_args) {
+    Ok(keymap) => keymap,
+    Err(err) => {
+      eprintln!("error: {}", err);
+      std::process::exit(1);
+    }
+  };
+  if replay.is_some() {
+    keymap.give_precedence(&replay::ACTIONS);
+  }
+  let palette_state = palette::PaletteState::from_args(&program_args, &particle_spec);
+  let renderer = program_args.renderer;
+  let primary_window = ui::primary_window(&program_args);
+  let mut app = App::new();
+  if let Some(recorder) = recorder {
+    app.insert_resource(recorder);
+  }
+  if let Some(frame_capture) = frame_capture {
+    // Step time by a fixed amount per frame, so every captured frame is the
+    // same distance apart in simulated time.
+    let frame_duration = frame_capture.frame_duration();
+    app
+      .insert_resource(TimeUpdateStrategy::ManualDuration(frame_duration))
+      .insert_resource(Time::<Virtual>::from_max_delta(frame_duration))
+      .insert_resource(frame_capture);
+  }
+  app
+    .insert_resource(particle_spec)
+    .insert_resource(spec_watch)
+    .insert_resource(palette_state)
+    .insert_resource(keymap)
+    .insert_resource(coloring::Coloring::from_args(&program_args))
+    .insert_resource(trails::TrailSettings::from_args(&program_args))
+    .insert_resource(density::DensitySettings::from_args(&program_args))
+    .insert_resource(index_overlay::IndexOverlay::from_args(&program_args))
+    .insert_resource(forces::ForceOverlay::from_args(&program_args))
+    .insert_resource(tiling::TilingSettings::from_args(&program_args))
     .insert_resource(program_args)
     .insert_resource(Time::<Fixed>::from_seconds(DELTA_TIME))
+    .init_resource::<reload::SpecReloadStatus>()
+    .init_resource::<observables::Observables>()
+    .init_resource::<observables::Contacts>()
+    .init_resource::<plots::ObservableHistory>()
+    .init_resource::<clusters::Clusters>()
+    .init_resource::<sim::SelectedParticle>()
     .add_plugins(DefaultPlugins.set(WindowPlugin {
-      primary_window: Some(Window {
-        resolution: WindowResolution::new(2560.0, 1440.0),
-        position: WindowPosition::Automatic,
-        resize_constraints: Default::default(),
-        title: "partikl".to_string(),
-        resizable: false,
-        decorations: false,
-        mode: WindowMode::BorderlessFullscreen(MonitorSelection::Primary),
-        transparent: false,
-        ..Default::default()
-      }),
+      primary_window: Some(primary_window),
       ..Default::default()
     }))
     .add_plugins(FrameTimeDiagnosticsPlugin::default())
@@ -38,27 +162,87 @@ fn main() {
       Startup,
       (render::init_materials, render::init_particles).chain(),
     )
-    .add_systems(Startup, ui::init_ui)
-    .add_systems(FixedUpdate, {
-      use sim::*;
+    .add_systems(
+      Startup,
       (
-        compute_forces.before(compute_friction),
-        compute_friction.before(integrate),
-        integrate,
-        wrap_around.after(integrate),
-        update_shape.after(integrate),
-      )
-    })
+        ui::init_ui,
+        plots::init_plots,
+        clusters::init_cluster_materials,
+        coloring::init_heatmap_materials,
+        density::init_density_overlay,
+        index_overlay::init_index_overlay,
+      ),
+    )
     .add_systems(
       Update,
       (
         sim::select_on_click,
         ui::update_text,
+        ui::update_spec_error,
+        plots::update_plots,
+        coloring::update_colors,
+        trails::draw_trails,
+        density::update_density_overlay,
+        index_overlay::draw_index_overlay,
+        index_overlay::update_bucket_labels,
+        forces::draw_force_vectors,
+        tiling::draw_dish_border,
+        palette::change_palette,
+        capture::take_screenshot,
+        capture::capture_frame,
+        reload::watch_spec_file,
         ui::exit_after_time,
         ui::handle_keyboard_input,
         ui::handle_mouse_input,
         ui::close_on_esc,
       ),
+    );
+  app
+    .add_systems(Startup, keymap::init_help)
+    .add_systems(
+      Update,
+      (
+        keymap::toggle_help,
+        ui::handle_camera_keys,
+        ui::animate_zoom
+          .after(ui::handle_mouse_input)
+          .after(ui::handle_camera_keys),
+      ),
     )
-    .run();
+    .insert_non_send_resource(dishes)
+    .add_systems(Startup, dishes::init_dishes.after(render::init_particles))
+    .add_systems(FixedUpdate, dishes::step_dishes)
+    .add_systems(
+      Update,
+      (
+        dishes::sync_dish_particles,
+        dishes::layout_viewports,
+        dishes::fit_views_to_window.before(ui::animate_zoom),
+        dishes::sync_dish_cameras.after(ui::animate_zoom),
+      ),
+    );
+  if renderer == args::Renderer::Instanced {
+    app.add_plugins(instanced::InstancedRenderPlugin);
+  }
+  if let Some(replay) = replay {
+    app
+      .insert_resource(replay)
+      .add_systems(Startup, replay::init_replay.after(render::init_particles))
+      .add_systems(Update, (replay::play_replay, replay::update_replay_text));
+  } else {
+    app
+      .add_systems(FixedUpdate, sim::step_systems())
+      .add_systems(Update, (tiling::recenter_on_cursor, sim::step_paused))
+      .add_systems(
+        FixedUpdate,
+        (
+          observables::update_observables.after(sim::wrap_around),
+          plots::record_history.after(observables::update_observables),
+          record::record_sample.after(observables::update_observables),
+          clusters::label_clusters.after(observables::update_observables),
+          trails::record_trails.after(sim::wrap_around),
+        ),
+      );
+  }
+  app.run();
 }
@@ -0,0 +1,491 @@
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+
+use serde::Serialize;
+
+use std::collections::HashMap;
+
+use crate::clusters;
+use crate::coloring::Coloring;
+use crate::core::*;
+use crate::plots::{self, PlotPanel};
+use crate::record::Recorder;
+
+/// Number of distance bins in each radial distribution function.
+pub const RDF_BINS: usize = 20;
+/// Smallest group of touching particles counted as a cluster.
+pub const MIN_CLUSTER_SIZE: usize = 5;
+
+/// State of every particle at one point in time.
+pub struct Snapshot {
+  pub entities: Vec<Entity>,
+  pub positions: Vec<Vec2>,
+  /// Displacement over the last tick.
+  pub velocities: Vec<Vec2>,
+  pub types: Vec<usize>,
+  /// Indices of the particles sharing or bordering each particle's bucket.
+  pub neighbours: Vec<Vec<usize>>,
+}
+
+impl Snapshot {
+  pub fn capture<'a>(
+    particles: impl Iterator<Item = (Entity, &'a Transform, &'a LastPosition, &'a InteractionId)>,
+    sim_region: &SimRegion,
+  ) -> Snapshot {
+    let mut indices = HashMap::new();
+    let mut entities = vec![];
+    let mut positions = vec![];
+    let mut velocities = vec![];
+    let mut types = vec![];
+    for (entity, transform, last_pos, interaction) in particles {
+      indices.insert(entity, positions.len());
+      entities.push(entity);
+      positions.push(transform.translation.xy());
+      velocities.push(transform.translation.xy() - last_pos.0);
+      types.push(interaction.0);
+    }
+    let neighbours = positions
+      .iter()
+      .map(|position| {
+        sim_region
+          .get_entities_by_position(position.x, position.y)
+          .filter_map(|entity| indices.get(&entity).copied())
+          .collect()
+      })
+      .collect();
+    Snapshot {
+      entities,
+      positions,
+      velocities,
+      types,
+      neighbours,
+    }
+  }
+}
+
+/// Summary measurements of the simulation, updated on the ticks something
+/// reads them.
+#[derive(Default, Resource, Debug, Clone, Serialize)]
+pub struct Observables {
+  /// Ticks run so far, counted whether or not the rest was computed.
+  pub tick: u64,
+  /// Sum of ½v² over all particles, taking every particle's mass as 1.
+  pub kinetic_energy: f32,
+  /// Mean speed in units per second, over all particles.
+  pub mean_speed: f32,
+  /// Mean speed in units per second, for each particle type.
+  pub mean_speed_by_type: Vec<f32>,
+  /// Groups of at least `MIN_CLUSTER_SIZE` particles, each within twice the
+  /// repulsion radius of another member.
+  pub cluster_count: usize,
+  /// Mean number of other particles within the interaction radius.
+  pub mean_neighbour_count: f32,
+  /// Radial distribution function g(r) of type `b` around type `a`, stored at
+  /// index `a * type_count + b`, covering distances up to the interaction
+  /// radius in `RDF_BINS` bins. Only plotted live, so left out of recordings.
+  #[serde(skip)]
+  pub rdf: Vec<Vec<f32>>,
+  #[serde(skip)]
+  pub rdf_bin_width: f32,
+  #[serde(skip)]
+  pub type_count: usize,
+}
+
+/// Particles within `cluster_distance` of each other, grouped by the last
+/// `update_observables`, so that labelling clusters doesn't redo the work.
+#[derive(Default, Resource)]
+pub struct Contacts {
+  pub entities: Vec<Entity>,
+  pub groups: DisjointSet,
+}
+
+impl Observables {
+  pub fn compute(
+    snapshot: &Snapshot,
+    sim_region: &SimRegion,
+    kernel: &KernelParams,
+    type_count: usize,
+  ) -> Observables {
+    Observables::compute_with_groups(snapshot, sim_region, kernel, type_count).0
+  }
+
+  /// Like `compute`, also returning the groups of touching particles that
+  /// the cluster count is based on.
+  pub fn compute_with_groups(
+    snapshot: &Snapshot,
+    sim_region: &SimRegion,
+    kernel: &KernelParams,
+    type_count: usize,
+  ) -> (Observables, DisjointSet) {
+    let n = snapshot.positions.len();
+    let speeds: Vec<f32> = snapshot
+      .velocities
+      .iter()
+      .map(|v| v.length() / DELTA_TIME as f32)
+      .collect();
+
+    let mut speed_sums = vec![0.0; type_count];
+    let mut type_counts = vec![0usize; type_count];
+    for (&t, &speed) in snapshot.types.iter().zip(speeds.iter()) {
+      speed_sums[t] += speed;
+      type_counts[t] += 1;
+    }
+    let mean_speed_by_type = speed_sums
+      .iter()
+      .zip(type_counts.iter())
+      .map(|(&sum, &count)| if count == 0 { 0.0 } else { sum / count as f32 })
+      .collect();
+
+    let radius = kernel.interaction_radius;
+    let join_distance = cluster_distance(kernel);
+    let rdf_bin_width = radius / RDF_BINS as f32;
+    let mut pair_counts = vec![vec![0usize; RDF_BINS]; type_count * type_count];
+    let mut neighbour_total = 0usize;
+    let mut clusters = DisjointSet::new(n);
+    for (i, candidates) in snapshot.neighbours.iter().enumerate() {
+      for &j in candidates.iter().filter(|&&j| j != i) {
+        let distance = sim_region
+          .get_corrected_position_delta(snapshot.positions[i], snapshot.positions[j])
+          .length();
+        if distance <= join_distance {
+          clusters.union(i, j);
+        }
+        if distance > radius {
+          continue;
+        }
+        neighbour_total += 1;
+        let bin = ((distance / rdf_bin_width) as usize).min(RDF_BINS - 1);
+        pair_counts[snapshot.types[i] * type_count + snapshot.types[j]][bin] += 1;
+      }
+    }
+
+    let dish = sim_region.size();
+    let area = dish.x * dish.y;
+    let rdf = pair_counts
+      .iter()
+      .enumerate()
+      .map(|(pair, counts)| {
+        let (a, b) = (pair / type_count, pair % type_count);
+        // A particle is never its own neighbour, so around a particle of type
+        // b only the other type_counts[b] - 1 are spread over the dish. This
+        // keeps g(r) at 1 for uncorrelated particles, however few there are.
+        let others_b = type_counts[b] - usize::from(a == b && type_counts[b] > 0);
+        let density_b = others_b as f32 / area;
+        counts
+          .iter()
+          .enumerate()
+          .map(|(bin, &count)| {
+            let inner = bin as f32 * rdf_bin_width;
+            let outer = inner + rdf_bin_width;
+            let shell_area = std::f32::consts::PI * (outer * outer - inner * inner);
+            let expected = type_counts[a] as f32 * density_b * shell_area;
+            if expected == 0.0 {
+              0.0
+            } else {
+              count as f32 / expected
+            }
+          })
+          .collect()
+      })
+      .collect();
+
+    let cluster_count = clusters
+      .component_sizes()
+      .into_iter()
+      .filter(|&size| size >= MIN_CLUSTER_SIZE)
+      .count();
+    let observables = Observables {
+      tick: 0,
+      kinetic_energy: speeds.iter().map(|speed| 0.5 * speed * speed).sum(),
+      mean_speed: if n == 0 {
+        0.0
+      } else {
+        speeds.iter().sum::<f32>() / n as f32
+      },
+      mean_speed_by_type,
+      cluster_count,
+      mean_neighbour_count: if n == 0 {
+        0.0
+      } else {
+        neighbour_total as f32 / n as f32
+      },
+      rdf,
+      rdf_bin_width,
+      type_count,
+    };
+    (observables, clusters)
+  }
+
+  pub fn rdf(&self, a: usize, b: usize) -> &[f32] {
+    &self.rdf[a * self.type_count + b]
+  }
+}
+
+/// Counts the tick, and computes the observables when the plots, the recorder
+/// or the cluster labels are going to read them.
+#[allow(clippy::too_many_arguments)]
+pub fn update_observables(
+  args: Res<ProgramArgs>,
+  state: Res<State<SimState>>,
+  particle_spec: Res<ParticleSpec>,
+  sim_region: Res<SimRegion>,
+  coloring: Res<Coloring>,
+  recorder: Option<Res<Recorder>>,
+  panel: Query<&Visibility, With<PlotPanel>>,
+  mut observables: ResMut<Observables>,
+  mut contacts: ResMut<Contacts>,
+  particles: Query<(Entity, &Transform, &LastPosition, &InteractionId)>,
+) {
+  if state.get() == &SimState::Paused {
+    return;
+  }
+  let tick = observables.tick + 1;
+  let wanted = plots::plots_shown(&panel)
+    || recorder.is_some_and(|recorder| recorder.samples(tick))
+    || clusters::labelling_due(&args, &coloring, tick);
+  if !wanted {
+    observables.tick = tick;
+    return;
+  }
+  let snapshot = Snapshot::capture(particles.iter(), &sim_region);
+  let (computed, groups) = Observables::compute_with_groups(
+    &snapshot,
+    &sim_region,
+    &particle_spec.kernel,
+    particle_spec.interactions.len(),
+  );
+  *observables = computed;
+  observables.tick = tick;
+  contacts.entities = snapshot.entities;
+  contacts.groups = groups;
+  if tick.is_multiple_of(100) {
+    debug!(
+      "tick {}: energy {}, speed {} (by type {:?}), {} clusters, {} neighbours on average",
+      tick,
+      observables.kinetic_energy,
+      observables.mean_speed,
+      observables.mean_speed_by_type,
+      observables.cluster_count,
+      observables.mean_neighbour_count
+    );
+  }
+}
+
+/// Distance within which two particles are considered part of the same
+/// cluster.
+pub fn cluster_distance(kernel: &KernelParams) -> f32 {
+  2.0 * kernel.repulsion_radius
+}
+
+/// Number of other particles within `radius` of each particle.
+pub fn neighbour_counts(snapshot: &Snapshot, sim_region: &SimRegion, radius: f32) -> Vec<usize> {
+  let radius_sq = radius * radius;
+  snapshot
+    .neighbours
+    .iter()
+    .enumerate()
+    .map(|(i, candidates)| {
+      candidates
+        .iter()
+        .filter(|&&j| {
+          j != i
+            && sim_region
+              .get_corrected_position_delta(snapshot.positions[i], snapshot.positions[j])
+              .length_squared()
+              <= radius_sq
+        })
+        .count()
+    })
+    .collect()
+}
+
+/// Union-find over particle indices.
+#[derive(Default)]
+pub struct DisjointSet {
+  parents: Vec<usize>,
+}
+
+impl DisjointSet {
+  pub fn new(size: usize) -> DisjointSet {
+    DisjointSet {
+      parents: (0..size).collect(),
+    }
+  }
+
+  pub fn find(&mut self, mut i: usize) -> usize {
+    while self.parents[i] != i {
+      self.parents[i] = self.parents[self.parents[i]];
+      i = self.parents[i];
+    }
+    i
+  }
+
+  pub fn union(&mut self, a: usize, b: usize) {
+    let root_a = self.find(a);
+    let root_b = self.find(b);
+    if root_a != root_b {
+      self.parents[root_a] = root_b;
+    }
+  }
+
+  pub fn component_sizes(&mut self) -> Vec<usize> {
+    let mut sizes: HashMap<usize, usize> = HashMap::new();
+    for i in 0..self.parents.len() {
+      *sizes.entry(self.find(i)).or_default() += 1;
+    }
+    sizes.into_values().collect()
+  }
+
+  /// Members of every group of at least `min_size`.
+  pub fn components(&mut self, min_size: usize) -> Vec<Vec<usize>> {
+    let mut components: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..self.parents.len() {
+      components.entry(self.find(i)).or_default().push(i);
+    }
+    components
+      .into_values()
+      .filter(|members| members.len() >= min_size)
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A snapshot of particles at rest, with every particle a neighbour
+  /// candidate of every other.
+  fn snapshot(positions: &[Vec2], types: &[usize]) -> Snapshot {
+    let n = positions.len();
+    Snapshot {
+      entities: (0..n as u32).map(Entity::from_raw).collect(),
+      positions: positions.to_vec(),
+      velocities: vec![Vec2::ZERO; n],
+      types: types.to_vec(),
+      neighbours: vec![(0..n).collect(); n],
+    }
+  }
+
+  fn dish() -> SimRegion {
+    SimRegion::new(1000.0, 1000.0, 40.0)
+  }
+
+  #[test]
+  fn disjoint_set_components() {
+    let mut set = DisjointSet::new(6);
+    set.union(0, 1);
+    set.union(2, 1);
+    set.union(3, 4);
+    set.union(4, 3);
+    assert_eq!(set.find(0), set.find(2));
+    assert_eq!(set.find(3), set.find(4));
+    assert_ne!(set.find(0), set.find(3));
+    assert_eq!(set.find(5), 5);
+    let mut sizes = set.component_sizes();
+    sizes.sort();
+    assert_eq!(sizes, vec![1, 2, 3]);
+    let mut components = set.components(2);
+    components.iter_mut().for_each(|members| members.sort());
+    components.sort();
+    assert_eq!(components, vec![vec![0, 1, 2], vec![3, 4]]);
+  }
+
+  #[test]
+  fn disjoint_set_long_chain() {
+    let mut set = DisjointSet::new(1000);
+    for i in 1..1000 {
+      set.union(i - 1, i);
+    }
+    assert_eq!(set.component_sizes(), vec![1000]);
+  }
+
+  #[test]
+  fn speeds_and_energy() {
+    let mut snapshot = snapshot(
+      &[Vec2::ZERO, Vec2::new(100.0, 0.0), Vec2::new(200.0, 0.0)],
+      &[0, 0, 1],
+    );
+    // Displacements per tick, i.e. speeds of 5 and 3 units per second.
+    let step = DELTA_TIME as f32;
+    snapshot.velocities = vec![
+      Vec2::new(3.0, 4.0) * step,
+      Vec2::new(3.0, 0.0) * step,
+      Vec2::ZERO,
+    ];
+    let observables = Observables::compute(&snapshot, &dish(), &KernelParams::default(), 3);
+    assert!((observables.kinetic_energy - (12.5 + 4.5)).abs() < 1e-3);
+    assert!((observables.mean_speed - 8.0 / 3.0).abs() < 1e-4);
+    let by_type = &observables.mean_speed_by_type;
+    assert_eq!(by_type.len(), 3);
+    assert!((by_type[0] - 4.0).abs() < 1e-4);
+    assert_eq!(by_type[1], 0.0);
+    // No particles of type 2 at all.
+    assert_eq!(by_type[2], 0.0);
+  }
+
+  #[test]
+  fn clusters_need_enough_members() {
+    let kernel = KernelParams::default();
+    let spacing = cluster_distance(&kernel) * 0.75;
+    let row = |count: usize, y: f32| (0..count).map(move |i| Vec2::new(i as f32 * spacing, y));
+    // A chain of five, one of four too small to count, and a lone particle.
+    let positions: Vec<Vec2> = row(5, 0.0)
+      .chain(row(4, 200.0))
+      .chain([Vec2::new(-300.0, -300.0)])
+      .collect();
+    let snapshot = snapshot(&positions, &vec![0; positions.len()]);
+    let observables = Observables::compute(&snapshot, &dish(), &kernel, 1);
+    assert_eq!(observables.cluster_count, 1);
+  }
+
+  #[test]
+  fn clusters_join_across_the_dish_edge() {
+    let kernel = KernelParams::default();
+    let spacing = cluster_distance(&kernel) * 0.75;
+    // Five particles in a row straddling the right and left edges.
+    let positions: Vec<Vec2> = (0..5)
+      .map(|i| {
+        let x = 500.0 - 2.0 * spacing + i as f32 * spacing;
+        Vec2::new(if x > 500.0 { x - 1000.0 } else { x }, 0.0)
+      })
+      .collect();
+    let snapshot = snapshot(&positions, &[0; 5]);
+    assert_eq!(
+      Observables::compute(&snapshot, &dish(), &kernel, 1).cluster_count,
+      1
+    );
+  }
+
+  #[test]
+  fn rdf_normalisation() {
+    let kernel = KernelParams::default();
+    let sim_region = dish();
+    // Two type 0 particles 5 apart, and a type 1 particle out of range.
+    let snapshot = snapshot(
+      &[Vec2::ZERO, Vec2::new(5.0, 0.0), Vec2::new(300.0, 300.0)],
+      &[0, 0, 1],
+    );
+    let observables = Observables::compute(&snapshot, &sim_region, &kernel, 2);
+    let width = observables.rdf_bin_width;
+    assert_eq!(width, kernel.interaction_radius / RDF_BINS as f32);
+    let bin = (5.0 / width) as usize;
+
+    // Each of the two sees the other once, against an expectation of
+    // n_a * ((n_b - 1) / area) * shell area.
+    let (inner, outer) = (bin as f32 * width, (bin + 1) as f32 * width);
+    let shell_area = std::f32::consts::PI * (outer * outer - inner * inner);
+    let area = sim_region.size().x * sim_region.size().y;
+    let expected = 2.0 * (1.0 / area) * shell_area;
+    let rdf = observables.rdf(0, 0);
+    assert_eq!(rdf.len(), RDF_BINS);
+    assert!((rdf[bin] - 2.0 / expected).abs() / rdf[bin] < 1e-4);
+    assert!(rdf
+      .iter()
+      .enumerate()
+      .all(|(other, &g)| other == bin || g == 0.0));
+    for (a, b) in [(0, 1), (1, 0), (1, 1)] {
+      assert!(observables.rdf(a, b).iter().all(|&g| g == 0.0));
+    }
+    assert_eq!(observables.mean_neighbour_count, 2.0 / 3.0);
+  }
+}
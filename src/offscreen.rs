@@ -0,0 +1,121 @@
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+
+use image::RgbaImage;
+
+use std::path::Path;
+
+use crate::core::*;
+use crate::headless::RunError;
+use crate::palette;
+use crate::raster::Canvas;
+
+const BACKGROUND: [u8; 4] = [0, 0, 0, 255];
+/// Particle radius in pixels below which particles aren't drawn any smaller,
+/// so they stay at least 1.5 pixels across and visible in small thumbnails.
+const MIN_RADIUS: f32 = 0.75;
+
+/// Draws particles into an image on the CPU, for runs without a GPU.
+///
+/// Particles are drawn as ellipses with the scale and rotation given to them
+/// by `sim::update_shape`, matching the meshes in the viewer.
+pub struct FrameRenderer {
+  dish: Vec2,
+  scale: f32,
+  particle_size: f32,
+  colors: Vec<[u8; 4]>,
+  width: u32,
+  height: u32,
+}
+
+impl FrameRenderer {
+  /// `scale` is the size of the image relative to the dish.
+  pub fn new(
+    particle_spec: &ParticleSpec,
+    dish: Vec2,
+    particle_size: f32,
+    scale: f32,
+  ) -> FrameRenderer {
+    let colors = palette::spec_colors(particle_spec);
+    FrameRenderer {
+      dish,
+      scale,
+      particle_size,
+      colors: colors
+        .iter()
+        .map(|color| color.to_srgba().to_u8_array())
+        .collect(),
+      width: ((dish.x * scale).round() as u32).max(1),
+      height: ((dish.y * scale).round() as u32).max(1),
+    }
+  }
+
+  pub fn render<'a>(
+    &self,
+    particles: impl Iterator<Item = (&'a Transform, &'a InteractionId)>,
+  ) -> RgbaImage {
+    let mut image = RgbaImage::new(self.width, self.height);
+    let mut canvas = Canvas::new(&mut image, self.width, self.height);
+    canvas.clear(BACKGROUND);
+    for (transform, interaction) in particles {
+      let position = transform.translation.xy();
+      let radii =
+        (transform.scale.xy() * self.particle_size * self.scale).max(Vec2::splat(MIN_RADIUS));
+      // Image rows run downwards, which mirrors the rotation.
+      let (angle, _, _) = transform.rotation.to_euler(EulerRot::ZYX);
+      let color = self
+        .colors
+        .get(interaction.0)
+        .copied()
+        .unwrap_or([255, 255, 255, 255]);
+      canvas.fill_ellipse(
+        (position.x + self.dish.x / 2.0) * self.scale,
+        (self.dish.y / 2.0 - position.y) * self.scale,
+        radii.x,
+        radii.y,
+        -angle,
+        color,
+      );
+    }
+    image
+  }
+}
+
+pub fn save_png(image: &RgbaImage, path: &Path) -> Result<(), RunError> {
+  image.save(path).map_err(|source| RunError::Image {
+    path: path.to_path_buf(),
+    source,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn particles_are_drawn_where_they_are_in_the_dish() {
+    let particle_spec = ParticleSpec {
+      interactions: vec![crate::core::Interaction {
+        force_coeffs: vec![0.0],
+      }],
+      info: SpecInfo {
+        colors: vec![[1.0, 0.0, 0.0]],
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    let renderer = FrameRenderer::new(&particle_spec, Vec2::new(20.0, 10.0), 2.0, 1.0);
+    let transform = Transform::from_xyz(5.0, 2.0, 0.0);
+    let image = renderer.render([(&transform, &InteractionId(0))].into_iter());
+
+    assert_eq!(image.dimensions(), (20, 10));
+    // The dish centre is the image centre, and y points up in the dish.
+    let red = [255, 0, 0, 255];
+    assert_eq!(image.get_pixel(15, 3).0, red);
+    assert_eq!(image.get_pixel(14, 2).0, red);
+    assert_eq!(image.get_pixel(4, 6).0, BACKGROUND);
+    assert_eq!(image.get_pixel(15, 6).0, BACKGROUND);
+    let drawn = image.pixels().filter(|pixel| pixel.0 == red).count();
+    assert_eq!(drawn, 12);
+  }
+}
@@ -0,0 +1,179 @@
+use bevy::prelude::*;
+use clap::ValueEnum;
+use rand::prelude::*;
+
+use crate::args::{Palette, ProgramArgs};
+use crate::core::*;
+use crate::generate;
+use crate::keymap::{Action, Actions};
+
+/// Anchors of the viridis color map, in sRGB.
+const VIRIDIS: [[f32; 3]; 5] = [
+  [0.267, 0.005, 0.329],
+  [0.229, 0.322, 0.546],
+  [0.128, 0.567, 0.551],
+  [0.369, 0.789, 0.383],
+  [0.993, 0.906, 0.144],
+];
+
+/// Palette currently applied in the viewer.
+#[derive(Resource, Debug)]
+pub struct PaletteState {
+  pub palette: Palette,
+  pub seed: u64,
+}
+
+impl PaletteState {
+  pub fn from_args(args: &ProgramArgs, particle_spec: &ParticleSpec) -> PaletteState {
+    PaletteState {
+      palette: args.palette.unwrap_or_default(),
+      seed: palette_seed(args, particle_spec),
+    }
+  }
+}
+
+pub fn palette_seed(args: &ProgramArgs, particle_spec: &ParticleSpec) -> u64 {
+  args
+    .palette_seed
+    .or(particle_spec.info.seed)
+    .unwrap_or_default()
+}
+
+/// Fills in the spec's colors from the palette given on the command line, or
+/// from the default palette if the spec has none.
+pub fn assign_colors(args: &ProgramArgs, particle_spec: &mut ParticleSpec) {
+  if args.palette.is_none() && !particle_spec.info.colors.is_empty() {
+    return;
+  }
+  particle_spec.info.colors = palette_colors(
+    args.palette.unwrap_or_default(),
+    particle_spec.interactions.len(),
+    palette_seed(args, particle_spec),
+  );
+}
+
+/// The spec's stored colors, or the default palette seeded by the spec if it
+/// has none.
+pub fn spec_colors(particle_spec: &ParticleSpec) -> Vec<Color> {
+  let colors = if particle_spec.info.colors.is_empty() {
+    palette_colors(
+      Palette::default(),
+      particle_spec.interactions.len(),
+      particle_spec.info.seed.unwrap_or_default(),
+    )
+  } else {
+    particle_spec.info.colors.clone()
+  };
+  colors
+    .into_iter()
+    .map(|[r, g, b]| Color::srgb(r, g, b))
+    .collect()
+}
+
+/// Picks one sRGB color per particle type. The same palette and seed always
+/// give the same colors.
+pub fn palette_colors(palette: Palette, type_count: usize, seed: u64) -> Vec<[f32; 3]> {
+  let mut rng = generate::seeded_rng(seed);
+  let phase = 360.0 * rng.gen::<f32>();
+  let colors = match palette {
+    Palette::Random => random_colors(type_count, &mut rng),
+    Palette::Rainbow => spaced_hues(type_count, phase, 360.0, 0.75, 0.7),
+    Palette::Pastel => spaced_hues(type_count, phase, 360.0, 0.9, 0.3),
+    Palette::Warm => spaced_hues(type_count, 20.0 + phase / 18.0, 70.0, 0.75, 0.8),
+    Palette::Cool => spaced_hues(type_count, 150.0 + phase / 18.0, 150.0, 0.7, 0.6),
+    Palette::Viridis => (0..type_count)
+      .map(|i| {
+        // The darkest end barely shows up against the black background.
+        let t = 0.15 + 0.85 * i as f32 / type_count.saturating_sub(1).max(1) as f32;
+        viridis(t)
+      })
+      .collect(),
+  };
+  colors
+    .into_iter()
+    .map(|color| {
+      let srgba = color.to_srgba();
+      [srgba.red, srgba.green, srgba.blue].map(|channel| channel.clamp(0.0, 1.0))
+    })
+    .collect()
+}
+
+fn random_colors(n: usize, rng: &mut impl Rng) -> Vec<Color> {
+  let phase = 360.0 * rng.gen::<f32>();
+  let spread = 60.0 + 300.0 * rng.gen::<f32>();
+  (0..n)
+    .map(|it| {
+      let mut hue = phase + spread * it as f32 / n as f32;
+      if hue > 360.0 {
+        hue -= 360.0;
+      }
+      Color::lch(
+        0.5 + 0.5 * rng.gen::<f32>(),
+        0.325 + 0.5 * rng.gen::<f32>(),
+        hue,
+      )
+    })
+    .collect()
+}
+
+/// Hues spread over an arc of `spread` degrees. Neighbouring types alternate
+/// in lightness, to tell them apart when the arc is narrow.
+fn spaced_hues(n: usize, start: f32, spread: f32, lightness: f32, chroma: f32) -> Vec<Color> {
+  let step = if spread >= 360.0 {
+    spread / n as f32
+  } else {
+    spread / n.saturating_sub(1).max(1) as f32
+  };
+  (0..n)
+    .map(|it| {
+      let hue = (start + step * it as f32) % 360.0;
+      let lightness = lightness - 0.15 * (it % 2) as f32;
+      Color::lch(lightness, chroma, hue)
+    })
+    .collect()
+}
+
+pub fn viridis(t: f32) -> Color {
+  let scaled = t.clamp(0.0, 1.0) * (VIRIDIS.len() - 1) as f32;
+  let index = (scaled as usize).min(VIRIDIS.len() - 2);
+  let [r0, g0, b0] = VIRIDIS[index];
+  let [r1, g1, b1] = VIRIDIS[index + 1];
+  let f = scaled - index as f32;
+  Color::srgb(r0 + (r1 - r0) * f, g0 + (g1 - g0) * f, b0 + (b1 - b0) * f)
+}
+
+pub fn change_palette(
+  actions: Actions,
+  mut palette_state: ResMut<PaletteState>,
+  mut particle_spec: ResMut<ParticleSpec>,
+  mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+  if actions.just_pressed(Action::NewPaletteSeed) {
+    palette_state.seed = thread_rng().gen();
+  } else if actions.just_pressed(Action::CyclePalette) {
+    let palettes = Palette::value_variants();
+    let index = palettes
+      .iter()
+      .position(|&palette| palette == palette_state.palette)
+      .unwrap_or_default();
+    palette_state.palette = palettes[(index + 1) % palettes.len()];
+  } else {
+    return;
+  }
+  let colors = palette_colors(
+    palette_state.palette,
+    particle_spec.interactions.len(),
+    palette_state.seed,
+  );
+  // Updating the materials in place recolors every particle using them.
+  for (handle, &[r, g, b]) in particle_spec.materials.iter().zip(colors.iter()) {
+    if let Some(material) = materials.get_mut(handle) {
+      material.base_color = Color::srgb(r, g, b);
+    }
+  }
+  particle_spec.info.colors = colors;
+  info!(
+    "palette {:?} with seed {}",
+    palette_state.palette, palette_state.seed
+  );
+}
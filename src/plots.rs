@@ -0,0 +1,336 @@
+use bevy::color::palettes::css::WHITE;
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+
+use std::collections::VecDeque;
+
+use crate::core::*;
+use crate::observables::{Observables, RDF_BINS};
+use crate::raster::Canvas;
+
+/// Number of ticks shown in the time-series plots.
+const HISTORY_LENGTH: usize = 600;
+const PLOT_WIDTH: u32 = 300;
+const PLOT_HEIGHT: u32 = 100;
+const BACKGROUND: [u8; 4] = [0, 0, 0, 160];
+const FOREGROUND: [u8; 4] = [255, 255, 255, 255];
+
+/// Rolling record of the observables shown in the plots.
+#[derive(Default, Resource)]
+pub struct ObservableHistory {
+  pub kinetic_energy: VecDeque<f32>,
+  pub mean_speed_by_type: Vec<VecDeque<f32>>,
+}
+
+#[derive(Resource)]
+pub struct PlotImages {
+  energy: Handle<Image>,
+  speed: Handle<Image>,
+  rdf: Handle<Image>,
+}
+
+#[derive(Component)]
+pub struct PlotPanel;
+#[derive(Component)]
+pub struct PlotLabel(usize);
+
+/// Whether the plot panel is on screen.
+pub fn plots_shown(panel: &Query<&Visibility, With<PlotPanel>>) -> bool {
+  panel
+    .get_single()
+    .is_ok_and(|visibility| visibility != Visibility::Hidden)
+}
+
+/// Adds the latest observables to the history. Observables are only computed
+/// every tick while the plots are shown, so the history only grows then.
+pub fn record_history(
+  state: Res<State<SimState>>,
+  observables: Res<Observables>,
+  panel: Query<&Visibility, With<PlotPanel>>,
+  mut history: ResMut<ObservableHistory>,
+) {
+  if state.get() == &SimState::Paused || !plots_shown(&panel) {
+    return;
+  }
+  let type_count = observables.mean_speed_by_type.len();
+  if history.mean_speed_by_type.len() != type_count {
+    *history = ObservableHistory {
+      mean_speed_by_type: vec![VecDeque::new(); type_count],
+      ..Default::default()
+    };
+  }
+  push_bounded(&mut history.kinetic_energy, observables.kinetic_energy);
+  for (series, &speed) in history
+    .mean_speed_by_type
+    .iter_mut()
+    .zip(observables.mean_speed_by_type.iter())
+  {
+    push_bounded(series, speed);
+  }
+}
+
+fn push_bounded(series: &mut VecDeque<f32>, value: f32) {
+  if series.len() == HISTORY_LENGTH {
+    series.pop_front();
+  }
+  series.push_back(value);
+}
+
+pub fn init_plots(
+  mut commands: Commands,
+  mut images: ResMut<Assets<Image>>,
+  asset_server: Res<AssetServer>,
+) {
+  let mut new_image = || {
+    images.add(Image::new_fill(
+      Extent3d {
+        width: PLOT_WIDTH,
+        height: PLOT_HEIGHT,
+        depth_or_array_layers: 1,
+      },
+      TextureDimension::D2,
+      &BACKGROUND,
+      TextureFormat::Rgba8UnormSrgb,
+      RenderAssetUsages::all(),
+    ))
+  };
+  let plot_images = PlotImages {
+    energy: new_image(),
+    speed: new_image(),
+    rdf: new_image(),
+  };
+  let font = asset_server.load("FiraMono-Regular.ttf");
+
+  commands
+    .spawn((
+      PlotPanel,
+      Visibility::Hidden,
+      Node {
+        position_type: PositionType::Absolute,
+        top: Val::Px(5.0),
+        right: Val::Px(5.0),
+        flex_direction: FlexDirection::Column,
+        row_gap: Val::Px(4.0),
+        ..Default::default()
+      },
+    ))
+    .with_children(|panel| {
+      for (index, image) in [&plot_images.energy, &plot_images.speed, &plot_images.rdf]
+        .into_iter()
+        .enumerate()
+      {
+        panel.spawn((
+          PlotLabel(index),
+          Text::default(),
+          TextFont {
+            font: font.clone(),
+            font_size: 12.0,
+            ..Default::default()
+          },
+          TextColor(WHITE.into()),
+        ));
+        panel.spawn((
+          ImageNode::new(image.clone()),
+          Node {
+            width: Val::Px(PLOT_WIDTH as f32),
+            height: Val::Px(PLOT_HEIGHT as f32),
+            ..Default::default()
+          },
+        ));
+      }
+    });
+  commands.insert_resource(plot_images);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_plots(
+  history: Res<ObservableHistory>,
+  observables: Res<Observables>,
+  particle_spec: Res<ParticleSpec>,
+  plot_images: Res<PlotImages>,
+  materials: Res<Assets<StandardMaterial>>,
+  panel: Query<&Visibility, With<PlotPanel>>,
+  labels: Query<(Entity, &PlotLabel)>,
+  mut images: ResMut<Assets<Image>>,
+  mut writer: TextUiWriter,
+) {
+  if !plots_shown(&panel) {
+    return;
+  }
+  let type_colors: Vec<[u8; 4]> = particle_spec
+    .materials
+    .iter()
+    .map(|handle| {
+      materials.get(handle).map_or(FOREGROUND, |material| {
+        material.base_color.to_srgba().to_u8_array()
+      })
+    })
+    .collect();
+
+  let energy_max = draw_series(
+    &mut images,
+    &plot_images.energy,
+    &[(&history.kinetic_energy, FOREGROUND)],
+  );
+  let speed_series: Vec<_> = history
+    .mean_speed_by_type
+    .iter()
+    .zip(
+      type_colors
+        .iter()
+        .copied()
+        .chain(std::iter::repeat(FOREGROUND)),
+    )
+    .collect();
+  let speed_max = draw_series(&mut images, &plot_images.speed, &speed_series);
+  let rdf_max = draw_rdf(&mut images, &plot_images.rdf, &observables);
+
+  for (entity, label) in labels.iter() {
+    *writer.text(entity, 0) = match label.0 {
+      0 => format!("kinetic energy (max {:.0})", energy_max),
+      1 => format!("mean speed by type (max {:.1})", speed_max),
+      _ => format!(
+        "g(r), r up to {:.0} (max {:.2})",
+        observables.rdf_bin_width * RDF_BINS as f32,
+        rdf_max
+      ),
+    };
+  }
+}
+
+/// Draws line plots sharing one vertical scale starting at zero, and returns
+/// the value at the top of the plot.
+fn draw_series(
+  images: &mut Assets<Image>,
+  handle: &Handle<Image>,
+  series: &[(&VecDeque<f32>, [u8; 4])],
+) -> f32 {
+  let image = match images.get_mut(handle) {
+    Some(image) => image,
+    None => return 0.0,
+  };
+  let mut canvas = Canvas::new(&mut image.data, PLOT_WIDTH, PLOT_HEIGHT);
+  canvas.clear(BACKGROUND);
+  let max = axis_max(series.iter().flat_map(|(values, _)| values.iter().copied()));
+  if max <= 0.0 {
+    return max;
+  }
+  let size = (canvas.width(), canvas.height());
+  for (values, color) in series {
+    let points: Vec<_> = values
+      .iter()
+      .enumerate()
+      .map(|(index, &value)| plot_point(index, value, max, size))
+      .collect();
+    for pair in points.windows(2) {
+      canvas.line(pair[0].0, pair[0].1, pair[1].0, pair[1].1, *color);
+    }
+  }
+  max
+}
+
+/// The value at the top of a plot of `values`, with zero at the bottom.
+fn axis_max(values: impl Iterator<Item = f32>) -> f32 {
+  values.fold(0.0f32, f32::max)
+}
+
+/// Pixel position of the `index`th value of a series in a plot of the given
+/// size, with the oldest of `HISTORY_LENGTH` values at the left edge and `max`
+/// at the top.
+fn plot_point(index: usize, value: f32, max: f32, (width, height): (u32, u32)) -> (i32, i32) {
+  let x = index as f32 / (HISTORY_LENGTH - 1) as f32 * (width - 1) as f32;
+  let y = (1.0 - value / max) * (height - 1) as f32;
+  (x.round() as i32, y.round() as i32)
+}
+
+/// g(r) averaged over all pairs of types.
+fn average_rdf(observables: &Observables) -> [f32; RDF_BINS] {
+  let type_count = observables.type_count;
+  let mut averaged = [0.0f32; RDF_BINS];
+  for a in 0..type_count {
+    for b in 0..type_count {
+      for (sum, value) in averaged.iter_mut().zip(observables.rdf(a, b)) {
+        *sum += value / (type_count * type_count) as f32;
+      }
+    }
+  }
+  averaged
+}
+
+/// Draws the radial distribution function averaged over all type pairs as a
+/// histogram, and returns the value at the top of the plot.
+fn draw_rdf(images: &mut Assets<Image>, handle: &Handle<Image>, observables: &Observables) -> f32 {
+  let image = match images.get_mut(handle) {
+    Some(image) => image,
+    None => return 0.0,
+  };
+  let mut canvas = Canvas::new(&mut image.data, PLOT_WIDTH, PLOT_HEIGHT);
+  canvas.clear(BACKGROUND);
+  if observables.type_count == 0 {
+    return 0.0;
+  }
+  let averaged = average_rdf(observables);
+  let max = axis_max(averaged.iter().copied());
+  if max <= 0.0 {
+    return max;
+  }
+  let bar_width = PLOT_WIDTH as i32 / RDF_BINS as i32;
+  let bottom = PLOT_HEIGHT as i32 - 1;
+  for (bin, value) in averaged.iter().enumerate() {
+    let x0 = bin as i32 * bar_width;
+    let top = ((1.0 - value / max) * bottom as f32).round() as i32;
+    canvas.fill_rect(x0, top, x0 + bar_width - 2, bottom, FOREGROUND);
+  }
+  max
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn history_keeps_the_latest_values() {
+    let mut series = VecDeque::new();
+    for value in 0..HISTORY_LENGTH + 3 {
+      push_bounded(&mut series, value as f32);
+    }
+    assert_eq!(series.len(), HISTORY_LENGTH);
+    assert_eq!(series.front(), Some(&3.0));
+    assert_eq!(series.back(), Some(&((HISTORY_LENGTH + 2) as f32)));
+  }
+
+  #[test]
+  fn axis_starts_at_zero() {
+    assert_eq!(axis_max([3.0, 7.5, 1.0].into_iter()), 7.5);
+    // Nothing to scale to, so nothing is drawn.
+    assert_eq!(axis_max([-2.0, -1.0].into_iter()), 0.0);
+    assert_eq!(axis_max(std::iter::empty()), 0.0);
+  }
+
+  #[test]
+  fn points_span_the_plot() {
+    let size = (PLOT_WIDTH, PLOT_HEIGHT);
+    let (right, bottom) = (PLOT_WIDTH as i32 - 1, PLOT_HEIGHT as i32 - 1);
+    assert_eq!(plot_point(0, 0.0, 4.0, size), (0, bottom));
+    assert_eq!(plot_point(HISTORY_LENGTH - 1, 4.0, 4.0, size), (right, 0));
+    assert_eq!(
+      plot_point(0, 2.0, 4.0, size).1,
+      (bottom as f32 / 2.0).round() as i32
+    );
+  }
+
+  #[test]
+  fn rdf_is_averaged_over_type_pairs() {
+    let mut observables = Observables {
+      type_count: 2,
+      rdf: vec![vec![0.0; RDF_BINS]; 4],
+      ..Default::default()
+    };
+    observables.rdf[1][0] = 4.0;
+    observables.rdf[2][0] = 2.0;
+    let averaged = average_rdf(&observables);
+    assert_eq!(averaged[0], 1.5);
+    assert!(averaged[1..].iter().all(|&g| g == 0.0));
+  }
+}
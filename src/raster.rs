@@ -0,0 +1,146 @@
+/// Minimal drawing on top of an RGBA8 pixel buffer, with the origin in the
+/// top-left corner.
+pub struct Canvas<'a> {
+  data: &'a mut [u8],
+  width: u32,
+  height: u32,
+}
+
+impl<'a> Canvas<'a> {
+  pub fn new(data: &'a mut [u8], width: u32, height: u32) -> Canvas<'a> {
+    assert_eq!(data.len(), (width * height * 4) as usize);
+    Canvas {
+      data,
+      width,
+      height,
+    }
+  }
+
+  pub fn width(&self) -> u32 {
+    self.width
+  }
+
+  pub fn height(&self) -> u32 {
+    self.height
+  }
+
+  pub fn clear(&mut self, color: [u8; 4]) {
+    for pixel in self.data.chunks_exact_mut(4) {
+      pixel.copy_from_slice(&color);
+    }
+  }
+
+  pub fn set_pixel(&mut self, x: i32, y: i32, color: [u8; 4]) {
+    if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+      return;
+    }
+    let offset = ((y as u32 * self.width + x as u32) * 4) as usize;
+    self.data[offset..offset + 4].copy_from_slice(&color);
+  }
+
+  pub fn fill_rect(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: [u8; 4]) {
+    for y in y0.min(y1)..=y0.max(y1) {
+      for x in x0.min(x1)..=x0.max(x1) {
+        self.set_pixel(x, y, color);
+      }
+    }
+  }
+
+  /// Bresenham's line algorithm.
+  pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: [u8; 4]) {
+    let dx = (x1 - x0).abs();
+    let dy = -(y1 - y0).abs();
+    let sx = if x0 < x1 { 1 } else { -1 };
+    let sy = if y0 < y1 { 1 } else { -1 };
+    let (mut x, mut y) = (x0, y0);
+    let mut error = dx + dy;
+    loop {
+      self.set_pixel(x, y, color);
+      if x == x1 && y == y1 {
+        break;
+      }
+      let doubled = 2 * error;
+      if doubled >= dy {
+        error += dy;
+        x += sx;
+      }
+      if doubled <= dx {
+        error += dx;
+        y += sy;
+      }
+    }
+  }
+
+  /// Fills an ellipse with radii `rx` along and `ry` across the direction
+  /// given by `angle`, in radians clockwise from the x axis.
+  pub fn fill_ellipse(&mut self, cx: f32, cy: f32, rx: f32, ry: f32, angle: f32, color: [u8; 4]) {
+    let (sin, cos) = angle.sin_cos();
+    let extent_x = ((rx * cos).powi(2) + (ry * sin).powi(2)).sqrt();
+    let extent_y = ((rx * sin).powi(2) + (ry * cos).powi(2)).sqrt();
+    let (x0, x1) = (
+      (cx - extent_x).floor() as i32,
+      (cx + extent_x).ceil() as i32,
+    );
+    let (y0, y1) = (
+      (cy - extent_y).floor() as i32,
+      (cy + extent_y).ceil() as i32,
+    );
+    for y in y0..=y1 {
+      for x in x0..=x1 {
+        let dx = x as f32 + 0.5 - cx;
+        let dy = y as f32 + 0.5 - cy;
+        let along = (dx * cos + dy * sin) / rx;
+        let across = (dy * cos - dx * sin) / ry;
+        if along * along + across * across <= 1.0 {
+          self.set_pixel(x, y, color);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SET: [u8; 4] = [255, 255, 255, 255];
+
+  fn filled(rx: f32, ry: f32, angle: f32) -> Vec<(i32, i32)> {
+    let mut data = vec![0; 10 * 10 * 4];
+    Canvas::new(&mut data, 10, 10).fill_ellipse(5.0, 5.0, rx, ry, angle, SET);
+    data
+      .chunks_exact(4)
+      .enumerate()
+      .filter(|(_, pixel)| *pixel == SET)
+      .map(|(i, _)| (i as i32 % 10, i as i32 / 10))
+      .collect()
+  }
+
+  #[test]
+  fn circle_covers_the_pixels_within_its_radius() {
+    let pixels = filled(2.0, 2.0, 0.0);
+    // Pixel centres within 2 of (5, 5).
+    assert_eq!(pixels.len(), 12);
+    for (x, y) in pixels {
+      let (dx, dy) = (x as f32 + 0.5 - 5.0, y as f32 + 0.5 - 5.0);
+      assert!(dx * dx + dy * dy <= 4.0, "({}, {})", x, y);
+    }
+  }
+
+  #[test]
+  fn ellipse_follows_its_angle() {
+    let flat = filled(4.0, 1.0, 0.0);
+    assert!(flat.contains(&(7, 5)) && !flat.contains(&(5, 6)));
+    let upright = filled(4.0, 1.0, std::f32::consts::FRAC_PI_2);
+    assert!(upright.contains(&(5, 7)) && !upright.contains(&(7, 5)));
+  }
+
+  #[test]
+  fn drawing_off_the_edge_is_clipped() {
+    let mut data = vec![0; 4 * 4 * 4];
+    let mut canvas = Canvas::new(&mut data, 4, 4);
+    canvas.fill_ellipse(0.0, 0.0, 3.0, 3.0, 0.0, SET);
+    canvas.line(-5, 1, 10, 1, SET);
+    assert_eq!(&data[0..4], &SET);
+  }
+}
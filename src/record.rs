@@ -0,0 +1,345 @@
+//! Streaming of observables, and optionally particle states, to disk.
+//!
+//! Samples are handed to a background thread, so writing never holds up the
+//! simulation. If the thread falls too far behind, samples are dropped rather
+//! than queued without limit.
+//!
+//! Every format is row-oriented, one record per sample, so a recording can be
+//! read back up to wherever the run stopped. The binary format is laid out as
+//! follows, all values little-endian:
+//!
+//! ```text
+//! header: b"PTKL", version: u32, dish width: f32, dish height: f32, interval: u64
+//! sample: tick: u64, kinetic energy: f32, mean speed: f32, cluster count: u32,
+//!         mean neighbour count: f32, type count: u32, mean speed per type: [f32],
+//!         particle count: u32, particles: [(id: u32, type: u32, x: f32, y: f32)]
+//! ```
+
+use bevy::prelude::*;
+
+use serde::Serialize;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use crate::args::{ProgramArgs, RecordFormat};
+use crate::core::*;
+use crate::observables::Observables;
+
+pub const BINARY_MAGIC: &[u8; 4] = b"PTKL";
+pub const BINARY_VERSION: u32 = 1;
+/// Samples waiting for the writer thread beyond which new ones are dropped.
+const QUEUE_LENGTH: usize = 256;
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct ParticleSample {
+  /// Stays the same for a given particle throughout the recording.
+  pub id: u32,
+  pub interaction: u32,
+  pub x: f32,
+  pub y: f32,
+}
+
+struct Sample {
+  dish: Vec2,
+  observables: Observables,
+  particles: Option<Vec<ParticleSample>>,
+}
+
+#[derive(Serialize)]
+struct JsonSample<'a> {
+  observables: &'a Observables,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  particles: Option<&'a [ParticleSample]>,
+}
+
+#[derive(Resource)]
+pub struct Recorder {
+  sender: Option<SyncSender<Sample>>,
+  writer: Option<JoinHandle<()>>,
+  interval: u64,
+  particles: bool,
+}
+
+impl Recorder {
+  /// Whether a sample is recorded on `tick`.
+  pub fn samples(&self, tick: u64) -> bool {
+    tick.is_multiple_of(self.interval)
+  }
+
+  /// Opens the output files requested on the command line, if any.
+  pub fn start(args: &ProgramArgs) -> io::Result<Option<Recorder>> {
+    let path = match &args.record {
+      Some(path) => path,
+      None => return Ok(None),
+    };
+    let output = BufWriter::new(File::create(path)?);
+    let particle_output = if args.record_particles && args.record_format == RecordFormat::Csv {
+      Some(BufWriter::new(File::create(particles_path(path))?))
+    } else {
+      None
+    };
+    let (sender, receiver) = sync_channel(QUEUE_LENGTH);
+    let format = args.record_format;
+    let interval = args.record_interval.max(1);
+    let writer = thread::spawn(move || {
+      if let Err(err) = write_samples(receiver, format, interval, output, particle_output) {
+        error!("recording stopped: {}", err);
+      }
+    });
+    Ok(Some(Recorder {
+      sender: Some(sender),
+      writer: Some(writer),
+      interval,
+      particles: args.record_particles,
+    }))
+  }
+
+  /// Hands a sample to the writer thread, or drops it if the thread can't
+  /// keep up.
+  pub fn record(
+    &self,
+    dish: Vec2,
+    observables: Observables,
+    particles: Option<Vec<ParticleSample>>,
+  ) {
+    let sender = match &self.sender {
+      Some(sender) => sender,
+      None => return,
+    };
+    let tick = observables.tick;
+    let sample = Sample {
+      dish,
+      observables,
+      particles,
+    };
+    match sender.try_send(sample) {
+      Ok(()) => {}
+      Err(TrySendError::Full(_)) => {
+        warn!(
+          "recording can't keep up, dropped the sample for tick {}",
+          tick
+        );
+      }
+      // The writer already failed and logged why.
+      Err(TrySendError::Disconnected(_)) => {}
+    }
+  }
+}
+
+impl Drop for Recorder {
+  fn drop(&mut self) {
+    // Closing the channel lets the writer drain what's left and finish.
+    self.sender.take();
+    if let Some(writer) = self.writer.take() {
+      let _ = writer.join();
+    }
+  }
+}
+
+fn particles_path(path: &Path) -> PathBuf {
+  let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+  let extension = path
+    .extension()
+    .map(|extension| format!(".{}", extension.to_string_lossy()))
+    .unwrap_or_default();
+  path.with_file_name(format!("{}-particles{}", stem, extension))
+}
+
+pub fn record_sample(
+  recorder: Option<Res<Recorder>>,
+  state: Res<State<SimState>>,
+  observables: Res<Observables>,
+  sim_region: Res<SimRegion>,
+  particles: Query<(Entity, &Transform, &InteractionId)>,
+) {
+  let recorder = match recorder {
+    Some(recorder) => recorder,
+    None => return,
+  };
+  if state.get() == &SimState::Paused || !recorder.samples(observables.tick) {
+    return;
+  }
+  let particles = recorder.particles.then(|| {
+    particles
+      .iter()
+      .map(|(entity, transform, interaction)| ParticleSample {
+        id: entity.index(),
+        interaction: interaction.0 as u32,
+        x: transform.translation.x,
+        y: transform.translation.y,
+      })
+      .collect()
+  });
+  recorder.record(sim_region.size(), observables.clone(), particles);
+}
+
+fn write_samples(
+  receiver: Receiver<Sample>,
+  format: RecordFormat,
+  interval: u64,
+  mut output: BufWriter<File>,
+  mut particle_output: Option<BufWriter<File>>,
+) -> io::Result<()> {
+  let mut header_written = false;
+  for sample in receiver {
+    let observables = &sample.observables;
+    match format {
+      RecordFormat::Csv => {
+        if !header_written {
+          writeln!(
+            output,
+            "tick,kinetic_energy,mean_speed,cluster_count,mean_neighbour_count,mean_speed_by_type"
+          )?;
+          if let Some(particle_output) = particle_output.as_mut() {
+            writeln!(particle_output, "tick,id,type,x,y")?;
+          }
+        }
+        writeln!(
+          output,
+          "{},{},{},{},{},{}",
+          observables.tick,
+          observables.kinetic_energy,
+          observables.mean_speed,
+          observables.cluster_count,
+          observables.mean_neighbour_count,
+          observables
+            .mean_speed_by_type
+            .iter()
+            .map(|speed| speed.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+        )?;
+        if let (Some(particle_output), Some(particles)) =
+          (particle_output.as_mut(), sample.particles.as_ref())
+        {
+          for particle in particles {
+            writeln!(
+              particle_output,
+              "{},{},{},{},{}",
+              observables.tick, particle.id, particle.interaction, particle.x, particle.y
+            )?;
+          }
+        }
+      }
+      RecordFormat::JsonLines => {
+        serde_json::to_writer(
+          &mut output,
+          &JsonSample {
+            observables,
+            particles: sample.particles.as_deref(),
+          },
+        )?;
+        writeln!(output)?;
+      }
+      RecordFormat::Binary => {
+        if !header_written {
+          output.write_all(BINARY_MAGIC)?;
+          output.write_all(&BINARY_VERSION.to_le_bytes())?;
+          output.write_all(&sample.dish.x.to_le_bytes())?;
+          output.write_all(&sample.dish.y.to_le_bytes())?;
+          output.write_all(&interval.to_le_bytes())?;
+        }
+        write_binary_sample(&mut output, &sample)?;
+      }
+    }
+    header_written = true;
+  }
+  output.flush()?;
+  if let Some(mut particle_output) = particle_output {
+    particle_output.flush()?;
+  }
+  Ok(())
+}
+
+fn write_binary_sample(output: &mut impl Write, sample: &Sample) -> io::Result<()> {
+  let observables = &sample.observables;
+  output.write_all(&observables.tick.to_le_bytes())?;
+  output.write_all(&observables.kinetic_energy.to_le_bytes())?;
+  output.write_all(&observables.mean_speed.to_le_bytes())?;
+  output.write_all(&(observables.cluster_count as u32).to_le_bytes())?;
+  output.write_all(&observables.mean_neighbour_count.to_le_bytes())?;
+  output.write_all(&(observables.mean_speed_by_type.len() as u32).to_le_bytes())?;
+  for speed in observables.mean_speed_by_type.iter() {
+    output.write_all(&speed.to_le_bytes())?;
+  }
+  let particles = sample.particles.as_deref().unwrap_or_default();
+  output.write_all(&(particles.len() as u32).to_le_bytes())?;
+  for particle in particles {
+    output.write_all(&particle.id.to_le_bytes())?;
+    output.write_all(&particle.interaction.to_le_bytes())?;
+    output.write_all(&particle.x.to_le_bytes())?;
+    output.write_all(&particle.y.to_le_bytes())?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use clap::Parser;
+
+  fn observables(tick: u64) -> Observables {
+    Observables {
+      tick,
+      kinetic_energy: 2.0,
+      mean_speed_by_type: vec![1.0, 3.0],
+      rdf: vec![vec![1.0; crate::observables::RDF_BINS]; 4],
+      type_count: 2,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn json_lines_hold_only_scalar_observables() {
+    let path = std::env::temp_dir().join(format!("partikl-json-{}.jsonl", std::process::id()));
+    let args = ProgramArgs::parse_from([
+      "partikl".as_ref(),
+      "--record".as_ref(),
+      path.as_os_str(),
+      "--record-format=json-lines".as_ref(),
+    ]);
+    let recorder = Recorder::start(&args).unwrap().unwrap();
+    recorder.record(Vec2::new(100.0, 100.0), observables(10), None);
+    recorder.record(Vec2::new(100.0, 100.0), observables(20), None);
+    drop(recorder);
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    let lines: Vec<serde_json::Value> = contents
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+    assert_eq!(lines.len(), 2);
+    let first = &lines[0]["observables"];
+    assert_eq!(first["tick"], 10);
+    assert_eq!(first["kinetic_energy"], 2.0);
+    assert_eq!(first["mean_speed_by_type"], serde_json::json!([1.0, 3.0]));
+    assert!(first.get("rdf").is_none());
+    assert!(lines[0].get("particles").is_none());
+  }
+
+  #[test]
+  fn samples_beyond_the_queue_are_dropped() {
+    let (sender, receiver) = sync_channel(QUEUE_LENGTH);
+    let recorder = Recorder {
+      sender: Some(sender),
+      writer: None,
+      interval: 1,
+      particles: false,
+    };
+    // Nothing reads the queue, yet recording never blocks.
+    for tick in 0..QUEUE_LENGTH as u64 + 10 {
+      recorder.record(Vec2::ONE, observables(tick), None);
+    }
+    drop(recorder);
+    let ticks: Vec<u64> = receiver
+      .iter()
+      .map(|sample| sample.observables.tick)
+      .collect();
+    assert_eq!(ticks, (0..QUEUE_LENGTH as u64).collect::<Vec<_>>());
+  }
+}
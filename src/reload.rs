@@ -0,0 +1,176 @@
+use bevy::prelude::*;
+use rand::prelude::*;
+
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::core::*;
+use crate::loading;
+use crate::render;
+
+const POLL_INTERVAL: f32 = 0.5;
+
+/// Outcome of the most recent attempt to reload the spec file.
+#[derive(Default, Resource)]
+pub struct SpecReloadStatus {
+  pub error: Option<String>,
+}
+
+type ReloadedParticles<'w, 's> = Query<
+  'w,
+  's,
+  (
+    Entity,
+    &'static Transform,
+    &'static mut InteractionId,
+    &'static mut MeshMaterial3d<StandardMaterial>,
+  ),
+>;
+
+/// Polls the spec file for changes made since it was loaded.
+#[derive(Resource)]
+pub struct SpecWatch {
+  timer: Timer,
+  last_modified: Option<SystemTime>,
+}
+
+impl SpecWatch {
+  /// Starts watching from the file's current modification time. Called before
+  /// the spec is first loaded, so that a later edit is never taken for the
+  /// version already running.
+  pub fn start(path: Option<&Path>) -> SpecWatch {
+    SpecWatch {
+      timer: Timer::from_seconds(POLL_INTERVAL, TimerMode::Repeating),
+      last_modified: path.and_then(modified_time),
+    }
+  }
+
+  /// Records the file's modification time, returning whether it changed.
+  fn changed(&mut self, modified: SystemTime) -> bool {
+    self.last_modified.replace(modified) != Some(modified)
+  }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+  fs::metadata(path)
+    .and_then(|metadata| metadata.modified())
+    .ok()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn watch_spec_file(
+  args: Res<ProgramArgs>,
+  time: Res<Time>,
+  mut watch: ResMut<SpecWatch>,
+  mut status: ResMut<SpecReloadStatus>,
+  mut particle_spec: ResMut<ParticleSpec>,
+  mut sim_region: ResMut<SimRegion>,
+  mut materials: ResMut<Assets<StandardMaterial>>,
+  mut particles: ReloadedParticles,
+) {
+  let path = match &args.interaction_spec {
+    Some(path) => path,
+    None => return,
+  };
+  if !watch.timer.tick(time.delta()).just_finished() {
+    return;
+  }
+  let modified = match modified_time(path) {
+    Some(modified) => modified,
+    None => return,
+  };
+  if !watch.changed(modified) {
+    return;
+  }
+
+  match loading::load_spec_file(path) {
+    Ok(new_spec) => {
+      info!("reloaded interaction spec from {:?}", path);
+      apply_spec(
+        new_spec,
+        &mut particle_spec,
+        &mut sim_region,
+        &mut materials,
+        &mut particles,
+      );
+      status.error = None;
+    }
+    Err(err) => {
+      warn!("keeping previous interaction spec: {}", err);
+      status.error = Some(err.to_string());
+    }
+  }
+}
+
+/// Swaps in a freshly loaded spec, keeping the existing particles.
+///
+/// Materials are only rebuilt when the number of types or the colors change;
+/// a spec without colors keeps the current ones if it has as many types.
+/// Particles whose type no longer exists are given a random remaining type.
+fn apply_spec(
+  mut new_spec: ParticleSpec,
+  particle_spec: &mut ParticleSpec,
+  sim_region: &mut SimRegion,
+  materials: &mut Assets<StandardMaterial>,
+  particles: &mut ReloadedParticles,
+) {
+  let type_count = new_spec.interactions.len();
+  if new_spec.info.colors.is_empty() && type_count == particle_spec.interactions.len() {
+    new_spec.info.colors = particle_spec.info.colors.clone();
+  }
+  let rebuild_materials = type_count != particle_spec.interactions.len()
+    || new_spec.info.colors != particle_spec.info.colors;
+  let rebuild_region = new_spec.kernel.interaction_radius != sim_region.granularity;
+
+  let old_materials = std::mem::take(&mut particle_spec.materials);
+  *particle_spec = new_spec;
+  particle_spec.materials = if rebuild_materials {
+    render::create_materials(particle_spec, materials)
+  } else {
+    old_materials
+  };
+
+  if rebuild_materials {
+    let mut rng = thread_rng();
+    for (_, _, mut interaction, mut material) in particles.iter_mut() {
+      if interaction.0 >= type_count {
+        interaction.0 = rng.gen_range(0..type_count);
+      }
+      material.0 = particle_spec.materials[interaction.0].clone();
+    }
+  }
+
+  if rebuild_region {
+    *sim_region = sim_region.with_granularity(particle_spec.kernel.interaction_radius);
+    for (entity, transform, _, _) in particles.iter() {
+      sim_region.insert_entity(entity, transform.translation.x, transform.translation.y);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn watch_starts_from_the_loaded_file() {
+    let path = std::env::temp_dir().join(format!("partikl-watch-{}.ron", std::process::id()));
+    fs::write(&path, "[]").unwrap();
+    let mut watch = SpecWatch::start(Some(&path));
+    let loaded = modified_time(&path);
+    let _ = fs::remove_file(&path);
+
+    let loaded = loaded.unwrap();
+    assert!(!watch.changed(loaded));
+    assert!(watch.changed(loaded + Duration::from_secs(1)));
+    assert!(!watch.changed(loaded + Duration::from_secs(1)));
+  }
+
+  #[test]
+  fn first_change_is_applied_without_a_starting_time() {
+    let mut watch = SpecWatch::start(None);
+    assert!(watch.changed(SystemTime::now()));
+  }
+}
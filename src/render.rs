@@ -1,45 +1,38 @@
-use crate::args::ProgramArgs;
+use crate::args::{ProgramArgs, Renderer};
 use bevy::prelude::*;
 use bevy::render::camera::ClearColorConfig;
 use bevy::render::camera::ScalingMode;
 use bevy::window::PrimaryWindow;
-use rand::prelude::*;
 
 use crate::core;
+use crate::palette;
+use crate::sim;
 
-fn get_random_colors(n: usize, rng: &mut impl Rng) -> Vec<Color> {
-  let phase = 360.0 * rng.gen::<f32>();
-  let spread = 60.0 + 300.0 * rng.gen::<f32>();
-  (0..n)
-    .map(|it| {
-      let mut hue = phase + spread * it as f32 / n as f32;
-      if hue > 360.0 {
-        hue -= 360.0;
-      }
-      Color::lch(
-        0.5 + 0.5 * rng.gen::<f32>(),
-        0.325 + 0.5 * rng.gen::<f32>(),
-        hue,
-      )
-      .into()
-    })
-    .collect()
-}
+/// Factor the view scale changes by per zoom step.
+pub const ZOOM_BASE: f32 = 1.125;
 
 pub fn init_materials(
   mut particle_spec: ResMut<core::ParticleSpec>,
   mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
-  let mut rng = thread_rng();
-  for color in get_random_colors(particle_spec.interactions.len(), &mut rng) {
-    let material = StandardMaterial {
-      base_color: color,
-      double_sided: true,
-      unlit: true,
-      ..Default::default()
-    };
-    particle_spec.materials.push(materials.add(material));
-  }
+  particle_spec.materials = create_materials(&particle_spec, &mut materials);
+}
+
+pub fn create_materials(
+  particle_spec: &core::ParticleSpec,
+  materials: &mut Assets<StandardMaterial>,
+) -> Vec<Handle<StandardMaterial>> {
+  palette::spec_colors(particle_spec)
+    .into_iter()
+    .map(|color| {
+      materials.add(StandardMaterial {
+        base_color: color,
+        double_sided: true,
+        unlit: true,
+        ..Default::default()
+      })
+    })
+    .collect()
 }
 
 pub fn init_particles(
@@ -50,49 +43,38 @@ pub fn init_particles(
   particle_spec: Res<core::ParticleSpec>,
   windows: Query<&Window, With<PrimaryWindow>>,
 ) {
-  let mut rng = thread_rng();
+  let seed = args.layout_seed.unwrap_or_default();
+  info!("particle layout seed: {}", seed);
+  let mut rng = sim::layout_rng(seed, 0);
   let window = windows.get_single().expect("no primary window");
   let width = window.width();
   let height = window.height();
-  let mut sim_region = core::SimRegion::new(width, height, 40.0);
+  let mut sim_region = core::SimRegion::new(width, height, particle_spec.kernel.interaction_radius);
 
   let circle_mesh = meshes.add(Mesh::try_from(Sphere::new(args.particle_size)).unwrap());
   let gizmo_mesh = meshes.add(Mesh::try_from(Sphere::new(args.particle_size + 3.0)).unwrap());
+  let selection_material = materials.add(Color::srgba(1.0, 1.0, 1.0, 0.5));
+  let highlight_material = materials.add(Color::srgba(1.0f32, 0.0, 0.5, 0.5));
 
   for _ in 0..args.num_particles {
-    let interaction = core::InteractionId(
-      (0..particle_spec.interactions.len())
-        .choose(&mut rng)
-        .expect("no particle spec"),
-    );
-    let position_x = rng.gen::<f32>() * width - width / 2.0;
-    let position_y = rng.gen::<f32>() * height - height / 2.0;
-    let translation = Vec3::new(position_x, position_y, 0.0);
-    let starting_velocity = Vec3::new(
-      rng.gen::<f32>() * 250f32 - 125f32,
-      rng.gen::<f32>() * 250f32 - 125f32,
-      0.0,
-    );
+    let (bundle, transform) =
+      sim::random_particle(&mut rng, particle_spec.interactions.len(), width, height);
+    let material = particle_spec.materials[bundle.interaction.0].clone();
+    // The instanced renderer reads each particle's color from its material, so
+    // the particles keep one even without a mesh of their own.
     let particle = commands
-      .spawn((
-        core::ParticleBundle {
-          acceleration: core::Acceleration(Vec2::new(0.0, 0.0)),
-          last_pos: core::LastPosition(
-            (translation - core::DELTA_TIME as f32 * starting_velocity).truncate(),
-          ),
-          interaction,
-        },
-        Transform::from_translation(translation),
-        Mesh3d(circle_mesh.clone()),
-        MeshMaterial3d(particle_spec.materials[interaction.0].clone()),
-      ))
+      .spawn((bundle, transform, MeshMaterial3d(material)))
       .id();
+    sim_region.insert_entity(particle, transform.translation.x, transform.translation.y);
+    if args.renderer == Renderer::Instanced {
+      continue;
+    }
     let particle_selection = commands
       .spawn((
         core::Selection::default(),
         Visibility::Hidden,
         Mesh3d(gizmo_mesh.clone()),
-        MeshMaterial3d(materials.add(Color::srgba(1.0, 1.0, 1.0, 0.5))),
+        MeshMaterial3d(selection_material.clone()),
       ))
       .id();
     let particle_highlight = commands
@@ -100,32 +82,60 @@ pub fn init_particles(
         core::Highlight::default(),
         Visibility::Hidden,
         Mesh3d(gizmo_mesh.clone()),
-        MeshMaterial3d(materials.add(Color::srgba(1.0f32, 0.0, 0.5, 0.5))),
+        MeshMaterial3d(highlight_material.clone()),
       ))
       .id();
     commands
       .entity(particle)
+      .insert(Mesh3d(circle_mesh.clone()))
       .add_children(&[particle_selection, particle_highlight]);
-    sim_region.insert_entity(particle, position_x, position_y);
   }
   commands.insert_resource(sim_region);
 
   commands.spawn((
-    core::MainCamera {
-      zoom_base: 1.125,
-      zoom_exponent: 1,
-    },
+    core::MainCamera,
+    camera(0, core::CameraZoom::new(ZOOM_BASE, 1)),
+  ));
+}
+
+/// View scale of an orthographic projection; larger shows more of the dish.
+pub fn projection_scale(projection: &Projection) -> f32 {
+  match projection {
+    Projection::Orthographic(orthographic) => orthographic.scale,
+    Projection::Perspective(_) => 1.0,
+  }
+}
+
+pub fn set_projection_scale(projection: &mut Projection, scale: f32) {
+  if let Projection::Orthographic(orthographic) = projection {
+    orthographic.scale = scale;
+  }
+}
+
+/// The smallest zoom exponent at which a dish fits in a viewport.
+pub fn fit_zoom_exponent(zoom_base: f32, dish: Vec2, viewport: Vec2) -> i32 {
+  let scale = (dish / viewport.max(Vec2::ONE)).max_element();
+  (scale.ln() / zoom_base.ln()).ceil() as i32
+}
+
+/// An orthographic camera looking down at the dish, at the given zoom.
+/// Cameras drawing to the same window need distinct `order`s.
+pub fn camera(order: isize, zoom: core::CameraZoom) -> impl Bundle {
+  (
+    zoom,
     Camera3d::default(),
     Camera {
       hdr: true,
+      order,
       clear_color: ClearColorConfig::Custom(Color::BLACK),
       ..Default::default()
     },
     Msaa::Sample4,
     Transform::from_xyz(0.0, 0.0, 1000.0).looking_at(Vec3::ZERO, Vec3::Y),
     Projection::from(OrthographicProjection {
+      scale: zoom.scale(),
       scaling_mode: ScalingMode::WindowSize,
       ..OrthographicProjection::default_3d()
     }),
-  ));
+  )
 }
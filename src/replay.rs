@@ -0,0 +1,420 @@
+use bevy::color::palettes::css::WHITE;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::core::*;
+use crate::keymap::{Action, Actions};
+use crate::observables::Observables;
+use crate::record::{ParticleSample, BINARY_MAGIC, BINARY_VERSION};
+use crate::sim;
+
+/// Fraction of the recording skipped by Shift+arrow keys.
+const SCRUB_FRACTION: f32 = 0.1;
+
+struct ReplayFrame {
+  observables: Observables,
+  /// Sorted by particle ID.
+  particles: Vec<ParticleSample>,
+}
+
+/// Actions that only make sense while replaying. They keep their bindings
+/// even where other actions, like panning, share them.
+pub const ACTIONS: [Action; 8] = [
+  Action::ReplayStepForward,
+  Action::ReplayStepBack,
+  Action::ReplaySkipForward,
+  Action::ReplaySkipBack,
+  Action::ReplayStart,
+  Action::ReplayEnd,
+  Action::ReplayFaster,
+  Action::ReplaySlower,
+];
+
+/// A trajectory recorded in the binary format, played back in place of the
+/// simulation.
+#[derive(Resource)]
+pub struct Replay {
+  frames: Vec<ReplayFrame>,
+  dish: Vec2,
+  interval: u64,
+  /// Current frame, fractional while between two samples.
+  position: f32,
+  /// Playback speed, relative to the speed the simulation ran at.
+  speed: f32,
+  entities: HashMap<u32, Entity>,
+}
+
+#[derive(Component)]
+pub struct ReplayText;
+
+type ReplayedParticles<'w, 's> = Query<
+  'w,
+  's,
+  (
+    Entity,
+    &'static mut Transform,
+    &'static mut InteractionId,
+    &'static mut MeshMaterial3d<StandardMaterial>,
+  ),
+>;
+
+impl Replay {
+  pub fn load(path: &Path) -> io::Result<Replay> {
+    let mut input = BufReader::new(File::open(path)?);
+    if &read_bytes::<4>(&mut input)? != BINARY_MAGIC {
+      return Err(invalid_data("not a binary recording".to_string()));
+    }
+    let version = u32::from_le_bytes(read_bytes(&mut input)?);
+    if version != BINARY_VERSION {
+      return Err(invalid_data(format!(
+        "recording format version {} is not the supported version {}",
+        version, BINARY_VERSION
+      )));
+    }
+    let dish = Vec2::new(read_f32(&mut input)?, read_f32(&mut input)?);
+    let interval = u64::from_le_bytes(read_bytes(&mut input)?);
+    let mut frames = vec![];
+    while !input.fill_buf()?.is_empty() {
+      frames.push(read_frame(&mut input)?);
+    }
+    if frames
+      .first()
+      .is_none_or(|frame| frame.particles.is_empty())
+    {
+      return Err(invalid_data(
+        "recording contains no particle states (record with --record-particles)".to_string(),
+      ));
+    }
+    Ok(Replay {
+      frames,
+      dish,
+      interval: interval.max(1),
+      position: 0.0,
+      speed: 1.0,
+      entities: HashMap::new(),
+    })
+  }
+
+  pub fn particle_count(&self) -> usize {
+    self.frames[0].particles.len()
+  }
+
+  pub fn type_count(&self) -> usize {
+    let frame = &self.frames[0];
+    let max_type = frame
+      .particles
+      .iter()
+      .map(|particle| particle.interaction as usize + 1)
+      .max()
+      .unwrap_or_default();
+    frame.observables.mean_speed_by_type.len().max(max_type)
+  }
+
+  fn last_frame(&self) -> f32 {
+    (self.frames.len() - 1) as f32
+  }
+}
+
+fn invalid_data(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_bytes<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+  let mut bytes = [0; N];
+  input.read_exact(&mut bytes)?;
+  Ok(bytes)
+}
+
+fn read_f32(input: &mut impl Read) -> io::Result<f32> {
+  Ok(f32::from_le_bytes(read_bytes(input)?))
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+  Ok(u32::from_le_bytes(read_bytes(input)?))
+}
+
+fn read_frame(input: &mut impl Read) -> io::Result<ReplayFrame> {
+  let tick = u64::from_le_bytes(read_bytes(input)?);
+  let kinetic_energy = read_f32(input)?;
+  let mean_speed = read_f32(input)?;
+  let cluster_count = read_u32(input)? as usize;
+  let mean_neighbour_count = read_f32(input)?;
+  let mean_speed_by_type = (0..read_u32(input)?)
+    .map(|_| read_f32(input))
+    .collect::<io::Result<_>>()?;
+  let mut particles = (0..read_u32(input)?)
+    .map(|_| {
+      Ok(ParticleSample {
+        id: read_u32(input)?,
+        interaction: read_u32(input)?,
+        x: read_f32(input)?,
+        y: read_f32(input)?,
+      })
+    })
+    .collect::<io::Result<Vec<_>>>()?;
+  particles.sort_by_key(|particle| particle.id);
+  Ok(ReplayFrame {
+    observables: Observables {
+      tick,
+      kinetic_energy,
+      mean_speed,
+      mean_speed_by_type,
+      cluster_count,
+      mean_neighbour_count,
+      ..Default::default()
+    },
+    particles,
+  })
+}
+
+/// Takes over the particles spawned by `render::init_particles`, giving each
+/// the type of a recorded particle and moving it into the recorded dish.
+pub fn init_replay(
+  mut commands: Commands,
+  mut replay: ResMut<Replay>,
+  particle_spec: Res<ParticleSpec>,
+  sim_region: Res<SimRegion>,
+  mut particles: ReplayedParticles,
+  asset_server: Res<AssetServer>,
+) {
+  let mut replay_region = SimRegion::new(replay.dish.x, replay.dish.y, sim_region.granularity);
+  let mut entities = HashMap::new();
+  for ((entity, mut transform, mut interaction, mut material), recorded) in
+    particles.iter_mut().zip(replay.frames[0].particles.iter())
+  {
+    interaction.0 = recorded.interaction as usize;
+    if let Some(type_material) = particle_spec.materials.get(interaction.0) {
+      material.0 = type_material.clone();
+    }
+    transform.translation.x = recorded.x;
+    transform.translation.y = recorded.y;
+    replay_region.insert_entity(entity, recorded.x, recorded.y);
+    entities.insert(recorded.id, entity);
+  }
+  replay.entities = entities;
+  commands.insert_resource(replay_region);
+
+  commands.spawn((
+    ReplayText,
+    Text::default(),
+    TextFont {
+      font: asset_server.load("FiraMono-Regular.ttf"),
+      font_size: 16.0,
+      ..Default::default()
+    },
+    TextColor(WHITE.into()),
+    Node {
+      position_type: PositionType::Absolute,
+      bottom: Val::Px(5.0),
+      right: Val::Px(5.0),
+      ..Default::default()
+    },
+  ));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn play_replay(
+  time: Res<Time>,
+  actions: Actions,
+  state: Res<State<SimState>>,
+  mut next_state: ResMut<NextState<SimState>>,
+  mut replay: ResMut<Replay>,
+  mut sim_region: ResMut<SimRegion>,
+  mut observables: ResMut<Observables>,
+  mut particles: Query<(&mut Transform, &mut LastPosition)>,
+  mut last_shown: Local<Option<f32>>,
+) {
+  let last_frame = replay.last_frame();
+  let skip = (last_frame * SCRUB_FRACTION).max(1.0);
+  let mut position = replay.position;
+  if actions.just_pressed(Action::ReplayStepForward) {
+    position = (position.floor() + 1.0).min(last_frame);
+  }
+  if actions.just_pressed(Action::ReplayStepBack) {
+    position = (position.ceil() - 1.0).max(0.0);
+  }
+  if actions.just_pressed(Action::ReplaySkipForward) {
+    position = (position.floor() + skip).min(last_frame);
+  }
+  if actions.just_pressed(Action::ReplaySkipBack) {
+    position = (position.ceil() - skip).max(0.0);
+  }
+  if actions.just_pressed(Action::ReplayStart) {
+    position = 0.0;
+  }
+  if actions.just_pressed(Action::ReplayEnd) {
+    position = last_frame;
+  }
+  if actions.just_pressed(Action::ReplayFaster) {
+    replay.speed *= 2.0;
+  }
+  if actions.just_pressed(Action::ReplaySlower) {
+    replay.speed /= 2.0;
+  }
+  if state.get() == &SimState::Running {
+    let frame_duration = replay.interval as f32 * DELTA_TIME as f32;
+    position += time.delta_secs() * replay.speed / frame_duration;
+    if position >= last_frame {
+      position = last_frame;
+      next_state.set(SimState::Paused);
+    }
+  }
+  replay.position = position;
+  if *last_shown == Some(position) {
+    return;
+  }
+  *last_shown = Some(position);
+
+  let index = position.floor() as usize;
+  let t = position.fract();
+  let current = &replay.frames[index];
+  let next = replay.frames.get(index + 1).unwrap_or(current);
+  for (i, recorded) in current.particles.iter().enumerate() {
+    let entity = match replay.entities.get(&recorded.id) {
+      Some(&entity) => entity,
+      None => continue,
+    };
+    let (mut transform, mut last_pos) = match particles.get_mut(entity) {
+      Ok(particle) => particle,
+      Err(_) => continue,
+    };
+    let start = Vec2::new(recorded.x, recorded.y);
+    let end = match next.particles.get(i) {
+      Some(following) if following.id == recorded.id => Vec2::new(following.x, following.y),
+      _ => start,
+    };
+    let delta = sim_region.get_corrected_position_delta(start, end);
+    let mut new_pos = start + t * delta;
+    new_pos += sim_region.get_wrap_around_adjustment(new_pos);
+    let velocity = delta / replay.interval as f32;
+    let old_pos = transform.translation.xy();
+    transform.translation = new_pos.extend(transform.translation.z);
+    last_pos.0 = new_pos - velocity;
+    let velocity_length_sq = velocity.length_squared();
+    if velocity_length_sq > VELOCITY_THRESHOLD {
+      transform.scale = sim::scale_from_velocity(velocity_length_sq);
+      transform.rotation = sim::rotation_from_velocity(velocity);
+    }
+    sim_region.move_entity(entity, old_pos.x, old_pos.y, new_pos.x, new_pos.y);
+  }
+  *observables = current.observables.clone();
+}
+
+pub fn update_replay_text(
+  replay: Res<Replay>,
+  state: Res<State<SimState>>,
+  actions: Actions,
+  query: Query<Entity, With<ReplayText>>,
+  mut writer: TextUiWriter,
+) {
+  let entity = match query.get_single() {
+    Ok(entity) => entity,
+    Err(_) => return,
+  };
+  let last_tick = replay.frames[replay.frames.len() - 1].observables.tick;
+  let tick = replay.frames[replay.position.floor() as usize]
+    .observables
+    .tick;
+  *writer.text(entity, 0) = format!(
+    "replay tick {}/{} at {}x{}\n{}/{} step, {}/{} speed, {} for help",
+    tick,
+    last_tick,
+    replay.speed,
+    if state.get() == &SimState::Paused {
+      ", paused"
+    } else {
+      ""
+    },
+    actions.describe(Action::ReplayStepBack),
+    actions.describe(Action::ReplayStepForward),
+    actions.describe(Action::ReplaySlower),
+    actions.describe(Action::ReplayFaster),
+    actions.describe(Action::Help),
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use clap::Parser;
+
+  use super::*;
+  use crate::args::ProgramArgs;
+  use crate::record::Recorder;
+
+  #[test]
+  fn binary_recordings_round_trip() {
+    let path = std::env::temp_dir().join(format!("partikl-round-trip-{}.bin", std::process::id()));
+    let args = ProgramArgs::parse_from([
+      "partikl".as_ref(),
+      "--record".as_ref(),
+      path.as_os_str(),
+      "--record-format=binary".as_ref(),
+      "--record-particles".as_ref(),
+      "--record-interval=5".as_ref(),
+    ]);
+    let particles = |tick: u64| {
+      // Out of ID order, as entity iteration order isn't sorted either.
+      vec![
+        ParticleSample {
+          id: 7,
+          interaction: 2,
+          x: tick as f32,
+          y: -1.5,
+        },
+        ParticleSample {
+          id: 3,
+          interaction: 0,
+          x: -(tick as f32),
+          y: 250.25,
+        },
+      ]
+    };
+    let recorder = Recorder::start(&args).unwrap().unwrap();
+    for tick in [5, 10] {
+      let observables = Observables {
+        tick,
+        kinetic_energy: 12.5,
+        mean_speed: 0.75,
+        mean_speed_by_type: vec![1.0, 0.0, 2.5, 0.0],
+        cluster_count: 3,
+        mean_neighbour_count: 4.25,
+        ..Default::default()
+      };
+      recorder.record(Vec2::new(640.0, 480.0), observables, Some(particles(tick)));
+    }
+    // Dropping the recorder waits for the writer to finish.
+    drop(recorder);
+
+    let replay = Replay::load(&path);
+    let _ = std::fs::remove_file(&path);
+    let replay = replay.unwrap();
+    assert_eq!(replay.dish, Vec2::new(640.0, 480.0));
+    assert_eq!(replay.interval, 5);
+    assert_eq!(replay.frames.len(), 2);
+    assert_eq!(replay.particle_count(), 2);
+    assert_eq!(replay.type_count(), 4);
+    for (frame, tick) in replay.frames.iter().zip([5, 10]) {
+      let observables = &frame.observables;
+      assert_eq!(observables.tick, tick);
+      assert_eq!(observables.kinetic_energy, 12.5);
+      assert_eq!(observables.mean_speed, 0.75);
+      assert_eq!(observables.mean_speed_by_type, vec![1.0, 0.0, 2.5, 0.0]);
+      assert_eq!(observables.cluster_count, 3);
+      assert_eq!(observables.mean_neighbour_count, 4.25);
+      let mut expected = particles(tick);
+      expected.sort_by_key(|particle| particle.id);
+      let fields = |particles: &[ParticleSample]| {
+        particles
+          .iter()
+          .map(|p| (p.id, p.interaction, p.x, p.y))
+          .collect::<Vec<_>>()
+      };
+      assert_eq!(fields(&frame.particles), fields(&expected));
+    }
+  }
+}
@@ -1,20 +1,100 @@
+use bevy::ecs::schedule::SystemConfigs;
 use bevy::math::Vec3Swizzles;
 use bevy::prelude::*;
 use bevy::utils::Parallel;
 use bevy::window::PrimaryWindow;
+use rand::prelude::*;
 
 use crate::core::*;
+use crate::generate;
+use crate::keymap::{Action, Actions, Keymap};
+use crate::ui;
+
+/// All systems advancing the simulation by one tick, in order.
+pub fn step_systems() -> SystemConfigs {
+  (
+    compute_forces.before(compute_friction),
+    compute_friction.before(integrate),
+    integrate,
+    wrap_around.after(integrate),
+    update_shape.after(integrate),
+  )
+    .into_configs()
+}
+
+/// Random number generator for laying out the particles of a dish, the main
+/// dish being dish 0. Each dish of a run gets its own sequence from one seed.
+pub fn layout_rng(seed: u64, dish: usize) -> impl Rng {
+  generate::seeded_rng(seed.wrapping_add((dish as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+}
+
+/// Creates a particle of random type at a random position within a dish of
+/// the given size, with a random starting velocity.
+pub fn random_particle(
+  rng: &mut impl Rng,
+  type_count: usize,
+  width: f32,
+  height: f32,
+) -> (ParticleBundle, Transform) {
+  let interaction = InteractionId((0..type_count).choose(rng).expect("no particle spec"));
+  let position_x = rng.gen::<f32>() * width - width / 2.0;
+  let position_y = rng.gen::<f32>() * height - height / 2.0;
+  let translation = Vec3::new(position_x, position_y, 0.0);
+  let starting_velocity = Vec3::new(
+    rng.gen::<f32>() * 250f32 - 125f32,
+    rng.gen::<f32>() * 250f32 - 125f32,
+    0.0,
+  );
+  (
+    ParticleBundle {
+      acceleration: Acceleration(Vec2::new(0.0, 0.0)),
+      last_pos: LastPosition((translation - DELTA_TIME as f32 * starting_velocity).truncate()),
+      trail: Trail::default(),
+      interaction,
+    },
+    Transform::from_translation(translation),
+  )
+}
 
 pub fn compute_forces(
   particle_spec: Res<ParticleSpec>,
   sim_region: Res<SimRegion>,
   state: Res<State<SimState>>,
+  selected: Option<ResMut<SelectedParticle>>,
   mut particles_out: Query<(Entity, &Transform, &mut Acceleration, &InteractionId)>,
   particles_in: Query<(Entity, &Transform, &InteractionId)>,
 ) {
   if state.get() == &SimState::Paused {
     return;
   }
+  // Keep the selected particle's terms for the force vector overlay, from the
+  // same positions its acceleration is computed from.
+  if let Some(mut selected) = selected {
+    let pair_forces = selected
+      .id
+      .and_then(|id| particles_in.get(id).ok())
+      .map(|(entity, transform, interaction)| {
+        let position = transform.translation.xy();
+        sim_region
+          .get_entities_by_position(position.x, position.y)
+          .filter(|&other| other != entity)
+          .filter_map(|other| {
+            let (_, other_transform, other_interaction) = particles_in.get(other).ok()?;
+            let delta =
+              sim_region.get_corrected_position_delta(position, other_transform.translation.xy());
+            let acceleration =
+              pair_acceleration(&particle_spec, interaction.0, other_interaction.0, delta)?;
+            Some(PairForce {
+              other,
+              acceleration,
+              attracting: acceleration.dot(delta) > 0.0,
+            })
+          })
+          .collect()
+      })
+      .unwrap_or_default();
+    selected.pair_forces = pair_forces;
+  }
   let mut queue: Parallel<Vec<(Entity, Vec2)>> = Parallel::default();
   particles_out.par_iter_mut().for_each_init(
     || queue.borrow_local_mut(),
@@ -24,66 +104,79 @@ pub fn compute_forces(
       let acceleration = neighbours
         .into_iter()
         .map(|other_entity| particles_in.get(other_entity).unwrap())
-        .fold(
-          Vec2::ZERO,
-          |mut acceleration, (other_entity, other_transform, other_interaction)| {
-            if entity == other_entity {
-              return acceleration;
-            }
-            let delta = sim_region.get_corrected_position_delta(
-              transform.translation.xy(),
-              other_transform.translation.xy(),
-            );
-            let distance_sq: f32 = delta.length_squared();
-            if distance_sq > 1600.0 {
-              return acceleration;
-            }
-            let distance = distance_sq.sqrt();
-            let distance_unit_vector = delta / distance;
-            if distance < 10.0 {
-              let safety_margin_repulsion_force =
-                (1000.0 - 100.0 * distance) * distance_unit_vector;
-              acceleration -= safety_margin_repulsion_force;
-            } else {
-              acceleration += triangular_kernel(
-                particle_spec.interactions[other_interaction.0].force_coeffs[interaction.0],
-                30.0,
-                10.0,
-                distance,
-              ) * distance_unit_vector;
-            }
-            acceleration
-          },
-        );
+        .filter(|(other_entity, ..)| *other_entity != entity)
+        .filter_map(|(_, other_transform, other_interaction)| {
+          let delta = sim_region.get_corrected_position_delta(
+            transform.translation.xy(),
+            other_transform.translation.xy(),
+          );
+          pair_acceleration(&particle_spec, interaction.0, other_interaction.0, delta)
+        })
+        .sum();
       local_queue.push((entity, acceleration))
     },
   );
 
+  // Every particle gets a fresh acceleration here, so the previous tick's
+  // stays readable (e.g. for coloring) until the next one is computed.
   for batch in queue.iter_mut() {
     for (entity, accel) in batch {
-      particles_out.get_mut(*entity).unwrap().2 .0 += *accel;
+      particles_out.get_mut(*entity).unwrap().2 .0 = *accel;
     }
   }
 }
 
 pub fn compute_friction(
+  particle_spec: Res<ParticleSpec>,
   state: Res<State<SimState>>,
   mut particles: Query<(&Transform, &mut LastPosition, &mut Acceleration)>,
 ) {
   if state.get() == &SimState::Paused {
     return;
   }
+  let friction = particle_spec.kernel.friction;
   for (transform, mut last_pos, mut acceleration) in particles.iter_mut() {
     let velocity = (transform.translation.xy() - last_pos.0) / DELTA_TIME as f32;
     if velocity.length_squared() < VELOCITY_THRESHOLD {
       last_pos.0 = transform.translation.xy();
     } else {
       let velocity_length = velocity.length();
-      acceleration.0 -= 0.01 * velocity * velocity_length;
+      acceleration.0 -= friction * velocity * velocity_length;
     }
   }
 }
 
+/// Acceleration of a particle of type `interaction` caused by one of type
+/// `other_interaction` at `delta` from it, or `None` if that's out of range.
+pub fn pair_acceleration(
+  particle_spec: &ParticleSpec,
+  interaction: usize,
+  other_interaction: usize,
+  delta: Vec2,
+) -> Option<Vec2> {
+  let kernel = &particle_spec.kernel;
+  let distance_sq: f32 = delta.length_squared();
+  if distance_sq > kernel.interaction_radius * kernel.interaction_radius {
+    return None;
+  }
+  let distance = distance_sq.sqrt();
+  let distance_unit_vector = delta / distance;
+  if distance < kernel.repulsion_radius {
+    let safety_margin_repulsion_force =
+      kernel.repulsion_strength * (1.0 - distance / kernel.repulsion_radius) * distance_unit_vector;
+    Some(-safety_margin_repulsion_force)
+  } else {
+    Some(
+      triangular_kernel(
+        particle_spec.interactions[other_interaction].force_coeffs[interaction],
+        kernel.peak_distance,
+        kernel.peak_width,
+        distance,
+      ) * distance_unit_vector,
+    )
+  }
+}
+
 #[allow(dead_code)]
 fn zigzag_kernel(magnitude: f32, middle: f32, width: f32, x: f32) -> f32 {
   magnitude * unit_zigzag((x - middle) / width)
@@ -104,15 +197,14 @@ fn unit_triangle(x: f32) -> f32 {
 
 pub fn integrate(
   state: Res<State<SimState>>,
-  mut query: Query<(&mut Acceleration, &mut Transform, &mut LastPosition)>,
+  mut query: Query<(&Acceleration, &mut Transform, &mut LastPosition)>,
 ) {
   if state.get() == &SimState::Paused {
     return;
   }
   let dt_sq = (DELTA_TIME * DELTA_TIME) as f32;
-  for (mut acceleration, mut transform, mut last_pos) in query.iter_mut() {
+  for (acceleration, mut transform, mut last_pos) in query.iter_mut() {
     let new_pos = 2.0 * transform.translation.xy() - last_pos.0 + acceleration.0 * dt_sq;
-    acceleration.0 = Vec2::ZERO;
     last_pos.0 = transform.translation.xy();
     transform.translation = new_pos.extend(0.0);
   }
@@ -155,88 +247,149 @@ pub fn update_shape(
   }
 }
 
-fn scale_from_velocity(velocity_length_sq: f32) -> Vec3 {
+pub fn scale_from_velocity(velocity_length_sq: f32) -> Vec3 {
   let coeff = (2.0 + velocity_length_sq).log2();
   Vec3::new(coeff, 0.75 / coeff + 0.25, 1.0)
 }
 
-fn rotation_from_velocity(velocity: Vec2) -> Quat {
+pub fn rotation_from_velocity(velocity: Vec2) -> Quat {
   let angle = velocity.angle_to(Vec2::new(1.0, 0.0));
   Quat::from_rotation_z(-angle)
 }
 
-#[derive(Default, Debug)]
-pub struct SelectedGizmo {
-  id: Option<Entity>,
-  translation: Vec3,
+/// The particle picked by clicking, and the particles sharing or bordering
+/// its spatial index bucket at the time.
+#[derive(Default, Debug, Resource)]
+pub struct SelectedParticle {
+  pub id: Option<Entity>,
+  pub neighbours: Vec<Entity>,
+  /// What each neighbour contributed to the particle's acceleration in the
+  /// last `compute_forces`.
+  pub pair_forces: Vec<PairForce>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PairForce {
+  pub other: Entity,
+  pub acceleration: Vec2,
+  /// Whether the acceleration points towards the other particle.
+  pub attracting: bool,
 }
 
+type Markers<'w, 's> = Query<
+  'w,
+  's,
+  (
+    Option<&'static Selection>,
+    Option<&'static Highlight>,
+    &'static mut Visibility,
+  ),
+  Or<(With<Selection>, With<Highlight>)>,
+>;
+
 pub fn select_on_click(
-  mouse_buttons: Res<ButtonInput<MouseButton>>,
+  actions: Actions,
   windows: Query<&Window, With<PrimaryWindow>>,
-  camera_query: Query<&Transform, With<MainCamera>>,
-  particles: Query<(Entity, &Transform, &Children), With<Acceleration>>,
+  camera_query: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+  particles: Query<(Entity, &Transform, Option<&Children>), With<Acceleration>>,
   sim_region: Res<SimRegion>,
-  mut gizmos: Query<
-    (Option<&Selection>, Option<&Highlight>, &mut Visibility),
-    Or<(With<Selection>, With<Highlight>)>,
-  >,
-  mut selected_gizmo: Local<SelectedGizmo>,
+  mut markers: Markers,
+  mut selected: ResMut<SelectedParticle>,
 ) {
-  if !mouse_buttons.just_released(MouseButton::Left) {
+  if !actions.just_released(Action::Select) {
     return;
   }
 
-  let window = windows.get_single().unwrap();
-  let cursor_position_opt = window.cursor_position();
-  if cursor_position_opt.is_none() {
+  let (window, (camera, camera_transform)) = match (windows.get_single(), camera_query.get_single())
+  {
+    (Ok(window), Ok(camera)) => (window, camera),
+    _ => return,
+  };
+  // Clicks in the viewports of other dishes leave the selection alone.
+  if !ui::cursor_over(window, camera) {
     return;
   }
-  let cursor_position = cursor_position_opt.unwrap();
-  let size = Vec2::new(window.width() as f32, window.height() as f32);
-  let cursor_position_offset = cursor_position - size / 2.0;
-  let camera_transform = camera_query.single();
-  let world_position = camera_transform.compute_matrix()
-    * cursor_position_offset.extend(0.0).extend(1.0)
-    * Vec4::from((1.0, -1.0, 1.0, 1.0));
+  let world_position = match ui::cursor_world_position(window, camera, camera_transform) {
+    Some(world_position) => world_position,
+    None => return,
+  };
 
-  for (_, _, mut visibility) in gizmos.iter_mut().filter(|(s, _, _)| s.is_some()) {
+  selected.id = particles
+    .iter()
+    .find(|(_, transform, _)| {
+      (transform.translation.xy() - world_position).length_squared() <= 16.0
+    })
+    .map(|(particle, _, _)| particle);
+  selected.neighbours = match selected.id {
+    Some(particle) => {
+      let (_, transform, _) = particles.get(particle).unwrap();
+      sim_region
+        .get_entities_by_position(transform.translation.x, transform.translation.y)
+        .collect()
+    }
+    None => vec![],
+  };
+
+  // With the mesh renderer, the markers are child entities of each particle.
+  for (_, _, mut visibility) in markers.iter_mut() {
     *visibility = Visibility::Hidden;
   }
-  selected_gizmo.id = None;
-  for (particle, transform, children) in particles.iter() {
-    if (transform.translation - world_position.truncate())
-      .truncate()
-      .length_squared()
-      > 16.0
-    {
-      continue;
-    }
-    selected_gizmo.id = Some(particle);
-    selected_gizmo.translation = transform.translation;
+  let mut show_marker = |particle: Entity, selection: bool| {
+    let children = match particles.get(particle) {
+      Ok((_, _, Some(children))) => children,
+      _ => return,
+    };
     for &child in children.iter() {
-      if let Ok((Some(_), None, mut visibility)) = gizmos.get_mut(child) {
-        *visibility = Visibility::Inherited;
-      }
-    }
-    break;
-  }
-
-  if let Some(particle) = selected_gizmo.id {
-    let (_, transform, _) = particles.get(particle).unwrap();
-    selected_gizmo.translation = transform.translation;
-    for (_, _, mut visibility) in gizmos.iter_mut().filter(|(_, h, _)| h.is_some()) {
-      *visibility = Visibility::Hidden;
-    }
-    let neighbour_ids = sim_region
-      .get_entities_by_position(selected_gizmo.translation.x, selected_gizmo.translation.y);
-    for nid in neighbour_ids {
-      let (_, _, children) = particles.get(nid).unwrap();
-      for &child in children.iter() {
-        if let Ok((None, Some(_), mut visibility)) = gizmos.get_mut(child) {
+      if let Ok((is_selection, is_highlight, mut visibility)) = markers.get_mut(child) {
+        if is_selection.is_some() == selection && is_highlight.is_some() != selection {
           *visibility = Visibility::Inherited;
         }
       }
     }
+  };
+  if let Some(particle) = selected.id {
+    show_marker(particle, true);
+  }
+  for &neighbour in selected.neighbours.iter() {
+    show_marker(neighbour, false);
+  }
+}
+
+/// Advances a paused simulation by a single tick.
+pub fn step_paused(world: &mut World) {
+  if world.resource::<State<SimState>>().get() != &SimState::Paused {
+    return;
+  }
+  let step = world.resource::<Keymap>().just_pressed(
+    Action::Step,
+    world.resource::<ButtonInput<KeyCode>>(),
+    world.resource::<ButtonInput<MouseButton>>(),
+  );
+  if !step {
+    return;
+  }
+  // The simulation systems skip ticks while paused, so run one as if it weren't.
+  world.insert_resource(State::new(SimState::Running));
+  world.run_schedule(FixedUpdate);
+  world.insert_resource(State::new(SimState::Paused));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn layout(seed: u64, dish: usize) -> Vec<Vec3> {
+    let mut rng = layout_rng(seed, dish);
+    (0..5)
+      .map(|_| random_particle(&mut rng, 3, 100.0, 100.0).1.translation)
+      .collect()
+  }
+
+  #[test]
+  fn layouts_are_reproducible_per_dish() {
+    assert_eq!(layout(7, 0), layout(7, 0));
+    assert_eq!(layout(7, 2), layout(7, 2));
+    assert_ne!(layout(7, 0), layout(7, 1));
+    assert_ne!(layout(7, 1), layout(8, 1));
   }
 }
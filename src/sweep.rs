@@ -0,0 +1,469 @@
+use chrono::offset::Utc;
+
+use ron::extensions::Extensions;
+
+use serde::{Deserialize, Serialize};
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::args::{Fitness, ProgramArgs, SweepArgs, TableFormat};
+use crate::core::*;
+use crate::fitness;
+use crate::generate::{self, GeneratorConfig};
+use crate::headless::{self, HeadlessSim, RunError};
+use crate::loading::{self, SpecError};
+use crate::offscreen::{self, FrameRenderer};
+
+/// Values taken by one parameter over the course of a sweep.
+#[derive(Deserialize, Debug)]
+pub enum Sweep<T> {
+  Fixed(T),
+  List(Vec<T>),
+  /// `steps` evenly spaced values from `start` to `end`, inclusive.
+  Range {
+    start: T,
+    end: T,
+    steps: usize,
+  },
+}
+
+pub trait SweepValue: Copy + PartialEq {
+  fn lerp(start: Self, end: Self, t: f64) -> Self;
+}
+
+impl SweepValue for f32 {
+  fn lerp(start: f32, end: f32, t: f64) -> f32 {
+    start + (end - start) * t as f32
+  }
+}
+
+impl SweepValue for u64 {
+  fn lerp(start: u64, end: u64, t: f64) -> u64 {
+    (start as f64 + (end as f64 - start as f64) * t).round() as u64
+  }
+}
+
+impl SweepValue for usize {
+  fn lerp(start: usize, end: usize, t: f64) -> usize {
+    (start as f64 + (end as f64 - start as f64) * t).round() as usize
+  }
+}
+
+impl<T: SweepValue> Sweep<T> {
+  pub fn values(&self) -> Vec<T> {
+    let mut values = match self {
+      Sweep::Fixed(value) => vec![*value],
+      Sweep::List(values) => values.clone(),
+      Sweep::Range { start, end, steps } => match steps {
+        0 => vec![],
+        1 => vec![*start],
+        _ => (0..*steps)
+          .map(|step| T::lerp(*start, *end, step as f64 / (*steps - 1) as f64))
+          .collect(),
+      },
+    };
+    // Integer ranges with more steps than values would repeat themselves.
+    values.dedup();
+    values
+  }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SweepDefinition {
+  pub seeds: Sweep<u64>,
+  #[serde(default)]
+  pub num_types: Option<Sweep<usize>>,
+  #[serde(default)]
+  pub num_particles: Option<Sweep<usize>>,
+  #[serde(default)]
+  pub repulsion_radius: Option<Sweep<f32>>,
+  #[serde(default)]
+  pub repulsion_strength: Option<Sweep<f32>>,
+  #[serde(default)]
+  pub peak_distance: Option<Sweep<f32>>,
+  #[serde(default)]
+  pub peak_width: Option<Sweep<f32>>,
+  #[serde(default)]
+  pub interaction_radius: Option<Sweep<f32>>,
+  #[serde(default)]
+  pub friction: Option<Sweep<f32>>,
+}
+
+/// Name of a swept kernel parameter, its values and how to apply one of them.
+type KernelAxis<'a> = (
+  &'static str,
+  &'a Option<Sweep<f32>>,
+  fn(&mut RunParams, f32),
+);
+
+/// Parameters of a single run within a sweep.
+#[derive(Debug, Clone)]
+struct RunParams {
+  seed: u64,
+  num_types: usize,
+  num_particles: usize,
+  kernel: KernelParams,
+}
+
+#[derive(Serialize, Debug)]
+struct RunResult {
+  run: usize,
+  seed: u64,
+  num_types: usize,
+  num_particles: usize,
+  repulsion_radius: f32,
+  repulsion_strength: f32,
+  peak_distance: f32,
+  peak_width: f32,
+  interaction_radius: f32,
+  friction: f32,
+  mean_speed: f32,
+  kinetic_energy: f32,
+  cluster_count: usize,
+  mean_neighbour_count: f32,
+  clustering: f32,
+  diversity: f32,
+  spec_file: String,
+}
+
+impl RunResult {
+  const CSV_HEADER: &'static str = "run,seed,num_types,num_particles,repulsion_radius,\
+    repulsion_strength,peak_distance,peak_width,interaction_radius,friction,mean_speed,\
+    kinetic_energy,cluster_count,mean_neighbour_count,clustering,diversity,spec_file";
+
+  fn to_csv_row(&self) -> String {
+    format!(
+      "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+      self.run,
+      self.seed,
+      self.num_types,
+      self.num_particles,
+      self.repulsion_radius,
+      self.repulsion_strength,
+      self.peak_distance,
+      self.peak_width,
+      self.interaction_radius,
+      self.friction,
+      self.mean_speed,
+      self.kinetic_energy,
+      self.cluster_count,
+      self.mean_neighbour_count,
+      self.clustering,
+      self.diversity,
+      self.spec_file
+    )
+  }
+}
+
+pub fn run(program_args: &ProgramArgs, sweep_args: &SweepArgs) -> Result<(), RunError> {
+  let config = GeneratorConfig::from_args(program_args);
+  config.validate()?;
+  headless::validate_headless_args(&sweep_args.headless)?;
+  let definition = load_definition(&sweep_args.definition)?;
+  let runs = expand(&definition, program_args)?;
+
+  let output_dir = sweep_args
+    .output_dir
+    .clone()
+    .unwrap_or_else(|| PathBuf::from(format!("sweep-{}", Utc::now().format("%F-%H-%M-%S"))));
+  fs::create_dir_all(&output_dir).map_err(|source| RunError::Io {
+    path: output_dir.clone(),
+    source,
+  })?;
+
+  let headless = &sweep_args.headless;
+  let mut results = vec![];
+  for (index, params) in runs.iter().enumerate() {
+    let mut rng = generate::seeded_rng(params.seed);
+    let mut particle_spec = generate::generate_particle_spec(&mut rng, params.num_types, &config);
+    particle_spec.kernel = params.kernel;
+    particle_spec.info.name = Some(format!("run-{:04}", index));
+    particle_spec.info.seed = Some(params.seed);
+    particle_spec.info.created = Some(Utc::now());
+    let spec_file = format!("run-{:04}.ron", index);
+    loading::write_spec_file(&particle_spec, &output_dir.join(&spec_file))?;
+
+    let mut sim = HeadlessSim::new(
+      particle_spec.clone(),
+      params.num_particles,
+      headless.dish_width,
+      headless.dish_height,
+      &mut rng,
+    );
+    sim.step(headless.ticks);
+    if sweep_args.thumbnails {
+      let renderer = FrameRenderer::new(
+        &particle_spec,
+        sim.sim_region().size(),
+        program_args.particle_size,
+        headless.image_scale,
+      );
+      let thumbnail_path = output_dir.join(format!("run-{:04}.png", index));
+      offscreen::save_png(&sim.render(&renderer), &thumbnail_path)?;
+    }
+    let observables = sim.observables();
+    let snapshot = sim.snapshot();
+    let score = |kind| fitness::metric(kind).score(&snapshot, sim.sim_region(), &params.kernel);
+    let result = RunResult {
+      run: index,
+      seed: params.seed,
+      num_types: params.num_types,
+      num_particles: params.num_particles,
+      repulsion_radius: params.kernel.repulsion_radius,
+      repulsion_strength: params.kernel.repulsion_strength,
+      peak_distance: params.kernel.peak_distance,
+      peak_width: params.kernel.peak_width,
+      interaction_radius: params.kernel.interaction_radius,
+      friction: params.kernel.friction,
+      mean_speed: observables.mean_speed,
+      kinetic_energy: observables.kinetic_energy,
+      cluster_count: observables.cluster_count,
+      mean_neighbour_count: observables.mean_neighbour_count,
+      clustering: score(Fitness::Clustering),
+      diversity: score(Fitness::Diversity),
+      spec_file,
+    };
+    println!(
+      "run {}/{}: speed {}, clustering {}, diversity {}",
+      index + 1,
+      runs.len(),
+      result.mean_speed,
+      result.clustering,
+      result.diversity
+    );
+    results.push(result);
+  }
+
+  let table_path = output_dir.join(match sweep_args.format {
+    TableFormat::Csv => "results.csv",
+    TableFormat::Json => "results.json",
+  });
+  write_table(&results, sweep_args.format, &table_path).map_err(|source| RunError::Io {
+    path: table_path.clone(),
+    source,
+  })?;
+  println!("results written to {:?}", table_path);
+  Ok(())
+}
+
+fn load_definition(path: &Path) -> Result<SweepDefinition, RunError> {
+  let contents = fs::read_to_string(path).map_err(|source| RunError::Io {
+    path: path.to_path_buf(),
+    source,
+  })?;
+  ron::Options::default()
+    .with_default_extension(Extensions::IMPLICIT_SOME)
+    .from_str(&contents)
+    .map_err(|source| RunError::Parse {
+      path: path.to_path_buf(),
+      source,
+    })
+}
+
+/// Lists every combination of swept values, with seeds varying fastest so
+/// that repeated runs of the same parameters end up next to each other.
+fn expand(
+  definition: &SweepDefinition,
+  program_args: &ProgramArgs,
+) -> Result<Vec<RunParams>, RunError> {
+  let mut runs = vec![RunParams {
+    seed: 0,
+    num_types: program_args.num_types,
+    num_particles: program_args.num_particles,
+    kernel: KernelParams::default(),
+  }];
+  runs = expand_axis(runs, "num_types", definition.num_types.as_ref(), |p, v| {
+    p.num_types = v
+  })?;
+  runs = expand_axis(
+    runs,
+    "num_particles",
+    definition.num_particles.as_ref(),
+    |p, v| p.num_particles = v,
+  )?;
+  let kernel_axes: [KernelAxis; 6] = [
+    ("repulsion_radius", &definition.repulsion_radius, |p, v| {
+      p.kernel.repulsion_radius = v
+    }),
+    (
+      "repulsion_strength",
+      &definition.repulsion_strength,
+      |p, v| p.kernel.repulsion_strength = v,
+    ),
+    ("peak_distance", &definition.peak_distance, |p, v| {
+      p.kernel.peak_distance = v
+    }),
+    ("peak_width", &definition.peak_width, |p, v| {
+      p.kernel.peak_width = v
+    }),
+    (
+      "interaction_radius",
+      &definition.interaction_radius,
+      |p, v| p.kernel.interaction_radius = v,
+    ),
+    ("friction", &definition.friction, |p, v| {
+      p.kernel.friction = v
+    }),
+  ];
+  for (name, sweep, set) in kernel_axes {
+    runs = expand_axis(runs, name, sweep.as_ref(), set)?;
+  }
+  runs = expand_axis(runs, "seeds", Some(&definition.seeds), |p, v| p.seed = v)?;
+
+  for params in runs.iter() {
+    if params.num_types == 0 {
+      return Err(SpecError::Empty.into());
+    }
+    loading::validate_kernel_params(&params.kernel)?;
+  }
+  Ok(runs)
+}
+
+fn expand_axis<T: SweepValue>(
+  runs: Vec<RunParams>,
+  name: &'static str,
+  sweep: Option<&Sweep<T>>,
+  set: impl Fn(&mut RunParams, T),
+) -> Result<Vec<RunParams>, RunError> {
+  let values = match sweep {
+    Some(sweep) => sweep.values(),
+    None => return Ok(runs),
+  };
+  if values.is_empty() {
+    return Err(RunError::SweepParam {
+      name,
+      reason: "sweeps over no values",
+    });
+  }
+  let set = &set;
+  Ok(
+    runs
+      .into_iter()
+      .flat_map(|params| {
+        values.iter().map(move |&value| {
+          let mut params = params.clone();
+          set(&mut params, value);
+          params
+        })
+      })
+      .collect(),
+  )
+}
+
+fn write_table(results: &[RunResult], format: TableFormat, path: &Path) -> std::io::Result<()> {
+  let mut writer = BufWriter::new(File::create(path)?);
+  match format {
+    TableFormat::Csv => {
+      writeln!(writer, "{}", RunResult::CSV_HEADER)?;
+      for result in results {
+        writeln!(writer, "{}", result.to_csv_row())?;
+      }
+    }
+    TableFormat::Json => {
+      serde_json::to_writer_pretty(&mut writer, results)?;
+      writeln!(writer)?;
+    }
+  }
+  writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use clap::Parser;
+
+  use super::*;
+
+  #[test]
+  fn sweep_values() {
+    let cases: [(&str, Sweep<f32>, Vec<f32>); 7] = [
+      ("fixed", Sweep::Fixed(2.0), vec![2.0]),
+      (
+        "list keeps order",
+        Sweep::List(vec![3.0, 1.0]),
+        vec![3.0, 1.0],
+      ),
+      ("empty list", Sweep::List(vec![]), vec![]),
+      (
+        "inclusive range",
+        Sweep::Range {
+          start: 0.0,
+          end: 1.0,
+          steps: 5,
+        },
+        vec![0.0, 0.25, 0.5, 0.75, 1.0],
+      ),
+      (
+        "descending range",
+        Sweep::Range {
+          start: 1.0,
+          end: 0.0,
+          steps: 3,
+        },
+        vec![1.0, 0.5, 0.0],
+      ),
+      (
+        "single step is the start",
+        Sweep::Range {
+          start: 4.0,
+          end: 8.0,
+          steps: 1,
+        },
+        vec![4.0],
+      ),
+      (
+        "no steps",
+        Sweep::Range {
+          start: 4.0,
+          end: 8.0,
+          steps: 0,
+        },
+        vec![],
+      ),
+    ];
+    for (label, sweep, expected) in cases {
+      assert_eq!(sweep.values(), expected, "{}", label);
+    }
+  }
+
+  #[test]
+  fn integer_ranges_drop_repeats() {
+    let sweep = Sweep::<usize>::Range {
+      start: 2,
+      end: 4,
+      steps: 10,
+    };
+    assert_eq!(sweep.values(), vec![2, 3, 4]);
+  }
+
+  fn expand_str(definition: &str) -> Result<Vec<RunParams>, RunError> {
+    let definition = ron::Options::default()
+      .with_default_extension(Extensions::IMPLICIT_SOME)
+      .from_str(definition)
+      .unwrap();
+    expand(&definition, &ProgramArgs::parse_from(["partikl"]))
+  }
+
+  #[test]
+  fn expand_varies_seeds_fastest() {
+    let runs =
+      expand_str("(seeds: List([1, 2]), num_types: List([3, 4]), friction: Fixed(0.1))").unwrap();
+    let params: Vec<_> = runs.iter().map(|run| (run.num_types, run.seed)).collect();
+    assert_eq!(params, vec![(3, 1), (3, 2), (4, 1), (4, 2)]);
+    assert!(runs.iter().all(|run| run.kernel.friction == 0.1));
+  }
+
+  #[test]
+  fn expand_rejects_empty_and_invalid_sweeps() {
+    let cases = [
+      "(seeds: List([]))",
+      "(seeds: Fixed(1), peak_width: Range(start: 1.0, end: 2.0, steps: 0))",
+      "(seeds: Fixed(1), num_types: Fixed(0))",
+      "(seeds: Fixed(1), friction: Fixed(-1.0))",
+    ];
+    for definition in cases {
+      assert!(expand_str(definition).is_err(), "{}", definition);
+    }
+  }
+}
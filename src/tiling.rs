@@ -0,0 +1,95 @@
+use bevy::color::palettes::css::GRAY;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+
+use crate::args::Renderer;
+use crate::core::*;
+use crate::keymap::{Action, Actions};
+use crate::ui;
+
+/// The border is drawn in front of the particles.
+const BORDER_DEPTH: f32 = 1.0;
+
+#[derive(Resource, Debug)]
+pub struct TilingSettings {
+  pub enabled: bool,
+}
+
+impl TilingSettings {
+  pub fn from_args(args: &ProgramArgs) -> TilingSettings {
+    TilingSettings {
+      enabled: args.tiled,
+    }
+  }
+
+  /// Offsets of the copies of the dish that are drawn, the dish itself first.
+  pub fn offsets(&self, dish: Vec2) -> Vec<Vec2> {
+    if !self.enabled {
+      return vec![Vec2::ZERO];
+    }
+    let mut offsets = vec![Vec2::ZERO];
+    for x in -1..=1 {
+      for y in -1..=1 {
+        if (x, y) != (0, 0) {
+          offsets.push(Vec2::new(x as f32, y as f32) * dish);
+        }
+      }
+    }
+    offsets
+  }
+}
+
+pub fn draw_dish_border(
+  settings: Res<TilingSettings>,
+  args: Res<ProgramArgs>,
+  sim_region: Res<SimRegion>,
+  mut gizmos: Gizmos,
+) {
+  if !settings.enabled {
+    return;
+  }
+  if settings.is_changed() && args.renderer == Renderer::Mesh {
+    warn!("tiling needs the instanced renderer, only the dish border is drawn");
+  }
+  gizmos.rect(
+    Isometry3d::from_translation(Vec3::Z * BORDER_DEPTH),
+    sim_region.size(),
+    GRAY,
+  );
+}
+
+/// Shifts every particle so that the point under the cursor ends up in the
+/// middle of the dish, wrapping them around its edges as they go.
+pub fn recenter_on_cursor(
+  actions: Actions,
+  windows: Query<&Window, With<PrimaryWindow>>,
+  camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+  mut sim_region: ResMut<SimRegion>,
+  mut particles: Query<(Entity, &mut Transform, &mut LastPosition, &mut Trail)>,
+) {
+  if !actions.just_pressed(Action::Recenter) {
+    return;
+  }
+  let (window, (camera, camera_transform)) = match (windows.get_single(), camera.get_single()) {
+    (Ok(window), Ok(camera)) => (window, camera),
+    _ => return,
+  };
+  let mut point = match ui::cursor_world_position(window, camera, camera_transform) {
+    Some(point) => point,
+    None => return,
+  };
+  // The cursor may be over one of the tiled copies.
+  point += sim_region.get_wrap_around_adjustment(point);
+
+  sim_region.index.clear();
+  for (entity, mut transform, mut last_pos, mut trail) in particles.iter_mut() {
+    let mut position = transform.translation.xy() - point;
+    let adjustment = sim_region.get_wrap_around_adjustment(position);
+    position += adjustment;
+    transform.translation = position.extend(transform.translation.z);
+    last_pos.0 += adjustment - point;
+    trail.clear();
+    sim_region.insert_entity(entity, position.x, position.y);
+  }
+}
@@ -0,0 +1,88 @@
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+
+use crate::core::*;
+
+/// Trails are drawn just behind the particles.
+const TRAIL_DEPTH: f32 = -1.0;
+
+#[derive(Resource, Debug)]
+pub struct TrailSettings {
+  pub enabled: bool,
+  pub length: usize,
+  pub interval: u64,
+}
+
+impl TrailSettings {
+  pub fn from_args(args: &ProgramArgs) -> TrailSettings {
+    TrailSettings {
+      enabled: args.trails,
+      length: args.trail_length,
+      interval: args.trail_interval.max(1),
+    }
+  }
+}
+
+pub fn record_trails(
+  settings: Res<TrailSettings>,
+  state: Res<State<SimState>>,
+  mut particles: Query<(&Transform, &mut Trail)>,
+  mut tick: Local<u64>,
+) {
+  if !settings.enabled {
+    // Dropping the old positions keeps a stale trail from connecting to where
+    // the particle is once trails come back on.
+    if settings.is_changed() {
+      for (_, mut trail) in particles.iter_mut() {
+        trail.clear();
+      }
+    }
+    return;
+  }
+  if state.get() == &SimState::Paused {
+    return;
+  }
+  *tick += 1;
+  if !tick.is_multiple_of(settings.interval) {
+    return;
+  }
+  for (transform, mut trail) in particles.iter_mut() {
+    trail.push(transform.translation.xy(), settings.length);
+  }
+}
+
+pub fn draw_trails(
+  settings: Res<TrailSettings>,
+  sim_region: Res<SimRegion>,
+  materials: Res<Assets<StandardMaterial>>,
+  particles: Query<(&Transform, &Trail, &MeshMaterial3d<StandardMaterial>)>,
+  mut gizmos: Gizmos,
+) {
+  if !settings.enabled {
+    return;
+  }
+  for (transform, trail, material) in particles.iter() {
+    let color = materials
+      .get(material)
+      .map_or(Color::WHITE, |material| material.base_color);
+    let points: Vec<Vec2> = trail
+      .iter()
+      .chain(std::iter::once(transform.translation.xy()))
+      .collect();
+    let segments = points.len().saturating_sub(1);
+    for (index, pair) in points.windows(2).enumerate() {
+      // A particle that wrapped around jumps across the dish; there's no
+      // segment to draw for that step.
+      let delta = pair[1] - pair[0];
+      if sim_region.get_corrected_position_delta(pair[0], pair[1]) != delta {
+        continue;
+      }
+      let alpha = (index + 1) as f32 / segments as f32;
+      gizmos.line(
+        pair[0].extend(TRAIL_DEPTH),
+        pair[1].extend(TRAIL_DEPTH),
+        color.with_alpha(alpha * 0.6),
+      );
+    }
+  }
+}
@@ -1,54 +1,154 @@
 use bevy::app::AppExit;
-use bevy::color::palettes::css::WHITE;
+use bevy::color::palettes::css::{RED, WHITE};
 use bevy::diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin};
 use bevy::input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel};
 use bevy::prelude::*;
-use bevy::render::camera::OrthographicProjection;
-use bevy::window::{CursorGrabMode, PrimaryWindow, WindowMode};
+use bevy::window::{CursorGrabMode, PresentMode, PrimaryWindow, WindowMode, WindowResolution};
 
+use crate::args::DisplayMode;
+use crate::coloring::Coloring;
 use crate::core::*;
+use crate::density::DensitySettings;
+use crate::forces::ForceOverlay;
+use crate::index_overlay::IndexOverlay;
+use crate::keymap::{Action, Actions};
+use crate::observables::Observables;
+use crate::plots::PlotPanel;
+use crate::reload::SpecReloadStatus;
+use crate::render;
+use crate::tiling::TilingSettings;
+use crate::trails::TrailSettings;
+
+/// Keyboard panning speed, in logical pixels per second.
+const KEY_PAN_SPEED: f32 = 600.0;
+/// How quickly the zoom catches up with its target, per second.
+const ZOOM_RATE: f32 = 12.0;
+/// Zooming stops once the scale is this close to its target, in log space.
+const ZOOM_SNAP: f32 = 1e-3;
 
 #[derive(Component)]
 pub struct FpsText;
+#[derive(Component)]
+pub struct SpecErrorText;
+
+/// The primary window, as configured on the command line.
+pub fn primary_window(args: &ProgramArgs) -> Window {
+  let monitor = monitor_selection(args);
+  Window {
+    resolution: WindowResolution::new(args.window_width, args.window_height),
+    position: WindowPosition::Centered(monitor),
+    resize_constraints: Default::default(),
+    title: "partikl".to_string(),
+    resizable: args.resizable,
+    decorations: args.display_mode == DisplayMode::Windowed,
+    mode: match args.display_mode {
+      DisplayMode::Windowed => WindowMode::Windowed,
+      _ => fullscreen_mode(args),
+    },
+    present_mode: if args.no_vsync {
+      PresentMode::AutoNoVsync
+    } else {
+      PresentMode::AutoVsync
+    },
+    transparent: false,
+    ..Default::default()
+  }
+}
+
+fn monitor_selection(args: &ProgramArgs) -> MonitorSelection {
+  args
+    .monitor
+    .map_or(MonitorSelection::Primary, MonitorSelection::Index)
+}
+
+/// The mode the fullscreen toggle switches to from a window.
+fn fullscreen_mode(args: &ProgramArgs) -> WindowMode {
+  match args.display_mode {
+    DisplayMode::Fullscreen => WindowMode::SizedFullscreen(monitor_selection(args)),
+    _ => WindowMode::BorderlessFullscreen(monitor_selection(args)),
+  }
+}
 
 pub fn init_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
+  let font = asset_server.load("FiraMono-Regular.ttf");
   commands
     .spawn((
       Text::new("hello"),
       TextFont {
-        font: asset_server.load("FiraMono-Regular.ttf"),
+        font: font.clone(),
         font_size: 16.0,
         ..Default::default()
       },
       TextColor(WHITE.into()),
     ))
     .insert(FpsText);
+  commands
+    .spawn((
+      Text::default(),
+      TextFont {
+        font,
+        font_size: 16.0,
+        ..Default::default()
+      },
+      TextColor(RED.into()),
+      Node {
+        position_type: PositionType::Absolute,
+        bottom: Val::Px(5.0),
+        left: Val::Px(5.0),
+        ..Default::default()
+      },
+    ))
+    .insert(SpecErrorText);
 }
 
 pub fn update_text(
   diagnostics: Res<DiagnosticsStore>,
+  observables: Res<Observables>,
+  coloring: Res<Coloring>,
   query: Query<Entity, With<FpsText>>,
   mut writer: TextUiWriter,
 ) {
   if let Some(fps) = diagnostics.get(&FrameTimeDiagnosticsPlugin::FPS) {
     if let Some(average) = fps.average() {
       let entity = query.single();
-      *writer.text(entity, 0) = format!("{:.2}", average);
+      *writer.text(entity, 0) = format!(
+        "{:.2}\nenergy {:.0}\nspeed {:.1}\nclusters {}\ncolor by {:?}",
+        average,
+        observables.kinetic_energy,
+        observables.mean_speed,
+        observables.cluster_count,
+        coloring.mode
+      );
     }
   }
 }
 
+pub fn update_spec_error(
+  status: Res<SpecReloadStatus>,
+  query: Query<Entity, With<SpecErrorText>>,
+  mut writer: TextUiWriter,
+) {
+  if !status.is_changed() {
+    return;
+  }
+  let entity = query.single();
+  *writer.text(entity, 0) = match &status.error {
+    Some(error) => format!("spec reload failed: {}", error),
+    None => String::new(),
+  };
+}
+
 pub fn close_on_esc(
   mut commands: Commands,
   focused_windows: Query<(Entity, &Window)>,
-  input: Res<ButtonInput<KeyCode>>,
+  actions: Actions,
 ) {
   for (window, focus) in focused_windows.iter() {
     if !focus.focused {
       continue;
     }
 
-    if input.just_pressed(KeyCode::Escape) {
+    if actions.just_pressed(Action::Quit) {
       commands.entity(window).despawn();
     }
   }
@@ -66,76 +166,253 @@ pub fn exit_after_time(
   }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn handle_keyboard_input(
-  keyboard: Res<ButtonInput<KeyCode>>,
+  args: Res<ProgramArgs>,
+  actions: Actions,
   state: Res<State<SimState>>,
   mut next_state: ResMut<NextState<SimState>>,
   mut windows: Query<&mut Window, With<PrimaryWindow>>,
+  mut plot_panel: Query<&mut Visibility, With<PlotPanel>>,
+  mut coloring: ResMut<Coloring>,
+  mut trail_settings: ResMut<TrailSettings>,
+  mut density_settings: ResMut<DensitySettings>,
+  mut index_overlay: ResMut<IndexOverlay>,
+  mut force_overlay: ResMut<ForceOverlay>,
+  mut tiling: ResMut<TilingSettings>,
 ) {
-  if keyboard.just_pressed(KeyCode::Space) {
+  if actions.just_pressed(Action::Pause) {
     let new_state = match state.get() {
       SimState::Running => SimState::Paused,
       SimState::Paused => SimState::Running,
     };
     next_state.set(new_state);
   }
-  if keyboard.just_pressed(KeyCode::KeyF) {
+  if actions.just_pressed(Action::Fullscreen) {
     let mut primary_window = windows.get_single_mut().unwrap();
     primary_window.mode = match primary_window.mode {
-      WindowMode::Windowed => WindowMode::BorderlessFullscreen(MonitorSelection::Primary),
+      WindowMode::Windowed => fullscreen_mode(&args),
       _ => WindowMode::Windowed,
     }
   }
+  if actions.just_pressed(Action::TogglePlots) {
+    for mut visibility in plot_panel.iter_mut() {
+      *visibility = match *visibility {
+        Visibility::Hidden => Visibility::Inherited,
+        _ => Visibility::Hidden,
+      };
+    }
+  }
+  if actions.just_pressed(Action::CycleColorMode) {
+    coloring.next_mode();
+  }
+  if actions.just_pressed(Action::ToggleTrails) {
+    trail_settings.enabled = !trail_settings.enabled;
+  }
+  if actions.just_pressed(Action::ToggleDensity) {
+    density_settings.enabled = !density_settings.enabled;
+  }
+  if actions.just_pressed(Action::ToggleIndexOverlay) {
+    index_overlay.enabled = !index_overlay.enabled;
+  }
+  if actions.just_pressed(Action::CycleForceVectors) {
+    force_overlay.next();
+  }
+  if actions.just_pressed(Action::ToggleTiling) {
+    tiling.enabled = !tiling.enabled;
+  }
+}
+
+/// The point in the dish under the cursor, if it's over the window.
+pub fn cursor_world_position(
+  window: &Window,
+  camera: &Camera,
+  camera_transform: &GlobalTransform,
+) -> Option<Vec2> {
+  let cursor_position = window.cursor_position()?;
+  let ray = camera
+    .viewport_to_world(camera_transform, cursor_position)
+    .ok()?;
+  Some(ray.origin.truncate())
+}
+
+/// The cursor's offset from the middle of the camera's viewport, in logical
+/// pixels with y pointing up.
+pub fn cursor_offset(window: &Window, camera: &Camera) -> Option<Vec2> {
+  let cursor_position = window.cursor_position()?;
+  let rect = camera.logical_viewport_rect()?;
+  Some((cursor_position - rect.center()) * Vec2::new(1.0, -1.0))
+}
+
+/// Zoom steps for one mouse wheel event, positive when scrolling up.
+pub fn scroll_steps(event: &MouseWheel) -> i32 {
+  match event {
+    MouseWheel {
+      unit: MouseScrollUnit::Line,
+      y,
+      ..
+    } => y.round() as i32,
+    MouseWheel {
+      unit: MouseScrollUnit::Pixel,
+      y,
+      ..
+    } => (y / 10.0).round() as i32,
+  }
+}
+
+/// Whether the cursor is over the part of the window the camera draws to.
+/// Without a cursor position, any camera counts.
+pub fn cursor_over(window: &Window, camera: &Camera) -> bool {
+  match (window.cursor_position(), camera.logical_viewport_rect()) {
+    (Some(cursor_position), Some(rect)) => rect.contains(cursor_position),
+    _ => true,
+  }
+}
+
+/// Whether the pan and zoom controls move `camera`. With several dishes, each
+/// viewport is panned and zoomed on its own unless the cameras are
+/// synchronised, in which case the others follow the main camera.
+fn controls_camera(
+  args: &ProgramArgs,
+  window: Option<&Window>,
+  camera: &Camera,
+  is_main: bool,
+) -> bool {
+  if args.sync_cameras {
+    return is_main;
+  }
+  window.map_or(is_main, |window| cursor_over(window, camera))
 }
 
 pub fn handle_mouse_input(
+  args: Res<ProgramArgs>,
+  actions: Actions,
   mut mouse_wheel_events: EventReader<MouseWheel>,
   mut mouse_motion_events: EventReader<MouseMotion>,
-  mouse_button_input: Res<ButtonInput<MouseButton>>,
-  keyboard_input: Res<ButtonInput<KeyCode>>,
   mut windows: Query<&mut Window, With<PrimaryWindow>>,
-  mut camera: Query<(&mut MainCamera, &mut OrthographicProjection, &mut Transform)>,
+  mut cameras: Query<(
+    &mut CameraZoom,
+    &mut Transform,
+    &Projection,
+    &Camera,
+    Has<MainCamera>,
+  )>,
 ) {
-  for event in mouse_wheel_events.read() {
-    let log_delta = match event {
-      MouseWheel {
-        unit: MouseScrollUnit::Line,
-        y,
-        ..
-      } => y.round() as i32,
-      MouseWheel {
-        unit: MouseScrollUnit::Pixel,
-        y,
-        ..
-      } => (y / 10.0).round() as i32,
-    };
-    let (mut main_camera, mut projection, _) = camera.get_single_mut().unwrap();
-    main_camera.zoom_exponent -= log_delta;
-    projection.scale = main_camera.zoom_base.powf(main_camera.zoom_exponent as f32);
+  let wheel_steps: i32 = mouse_wheel_events.read().map(scroll_steps).sum();
+  let mut zoom_steps = wheel_steps;
+  if actions.just_pressed(Action::ZoomIn) {
+    zoom_steps += 1;
+  }
+  if actions.just_pressed(Action::ZoomOut) {
+    zoom_steps -= 1;
   }
+  let drag: Vec2 = mouse_motion_events.read().map(|event| event.delta).sum();
+  let panning = actions.pressed(Action::Pan);
 
-  if mouse_button_input.just_released(MouseButton::Left) {
+  {
+    let window = windows.get_single().ok();
+    for (mut zoom, mut transform, projection, camera, is_main) in cameras.iter_mut() {
+      if !controls_camera(&args, window, camera, is_main) {
+        continue;
+      }
+      if zoom_steps != 0 {
+        // The wheel zooms in on the cursor, the keys on the middle of the view.
+        zoom.anchor = window
+          .filter(|_| wheel_steps != 0)
+          .and_then(|window| cursor_offset(window, camera))
+          .unwrap_or(Vec2::ZERO);
+        zoom.exponent -= zoom_steps;
+      }
+      if panning {
+        transform.translation +=
+          (drag * Vec2::new(-1.0, 1.0)).extend(0.0) * render::projection_scale(projection);
+      }
+    }
+  }
+
+  if actions.just_released(Action::Pan) {
     let mut primary_window = windows.get_single_mut().unwrap();
     primary_window.cursor_options.visible = true;
     primary_window.cursor_options.grab_mode = CursorGrabMode::Locked;
   }
 
-  if !keyboard_input.pressed(KeyCode::ControlLeft) {
-    return;
-  }
-
-  if mouse_button_input.just_pressed(MouseButton::Left) {
+  if actions.just_pressed(Action::Pan) {
     let mut primary_window = windows.get_single_mut().unwrap();
     primary_window.cursor_options.visible = false;
     primary_window.cursor_options.grab_mode = CursorGrabMode::None;
   }
+}
+
+/// Pans the view with the keyboard, and jumps back to the starting view or to
+/// one showing the whole dish.
+pub fn handle_camera_keys(
+  time: Res<Time>,
+  args: Res<ProgramArgs>,
+  actions: Actions,
+  sim_region: Res<SimRegion>,
+  windows: Query<&Window, With<PrimaryWindow>>,
+  mut cameras: Query<(
+    &mut CameraZoom,
+    &mut Transform,
+    &Projection,
+    &Camera,
+    Has<MainCamera>,
+  )>,
+) {
+  let window = windows.get_single().ok();
+  let axis =
+    |positive, negative| actions.pressed(positive) as i32 - actions.pressed(negative) as i32;
+  let direction = Vec2::new(
+    axis(Action::PanRight, Action::PanLeft) as f32,
+    axis(Action::PanUp, Action::PanDown) as f32,
+  );
+
+  for (mut zoom, mut transform, projection, camera, is_main) in cameras.iter_mut() {
+    if !controls_camera(&args, window, camera, is_main) {
+      continue;
+    }
+    if direction != Vec2::ZERO {
+      transform.translation += (direction.normalize()
+        * KEY_PAN_SPEED
+        * time.delta_secs()
+        * render::projection_scale(projection))
+      .extend(0.0);
+    }
 
-  for event in mouse_motion_events.read() {
-    if !mouse_button_input.pressed(MouseButton::Left) {
+    let exponent = if actions.just_pressed(Action::ResetView) {
+      zoom.home_exponent
+    } else if actions.just_pressed(Action::FitDish) {
+      let viewport = camera.logical_viewport_size().unwrap_or(sim_region.size());
+      render::fit_zoom_exponent(zoom.base, sim_region.size(), viewport)
+    } else {
+      continue;
+    };
+    transform.translation = Vec3::new(0.0, 0.0, transform.translation.z);
+    zoom.anchor = Vec2::ZERO;
+    zoom.exponent = exponent;
+  }
+}
+
+/// Eases the projection scale towards the zoom level, keeping the world point
+/// at the zoom anchor in place.
+pub fn animate_zoom(
+  time: Res<Time>,
+  args: Res<ProgramArgs>,
+  mut cameras: Query<(&CameraZoom, &mut Projection, &mut Transform)>,
+) {
+  for (zoom, mut projection, mut transform) in cameras.iter_mut() {
+    let current = render::projection_scale(&projection);
+    let target = zoom.scale();
+    if current == target {
       continue;
     }
-    let (_, projection, mut camera_transform) = camera.get_single_mut().unwrap();
-    camera_transform.translation +=
-      (event.delta * Vec2::new(-1.0, 1.0)).extend(0.0) * projection.scale;
+    let remaining = target / current;
+    let scale = if args.instant_zoom || remaining.ln().abs() < ZOOM_SNAP {
+      target
+    } else {
+      current * remaining.powf(1.0 - (-ZOOM_RATE * time.delta_secs()).exp())
+    };
+    transform.translation += (zoom.anchor * (current - scale)).extend(0.0);
+    render::set_projection_scale(&mut projection, scale);
   }
 }